@@ -0,0 +1,176 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer};
+
+/// A network in CIDR notation, or a single address.
+#[derive(Clone, Copy)]
+pub struct Cidr(IpNet);
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Cidr(net));
+        }
+        s.parse::<IpAddr>()
+            .map(|ip| Cidr(IpNet::from(ip)))
+            .map_err(|_| format!("invalid CIDR or IP address: {}", s))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+fn matches(nets: &[Cidr], ip: &IpAddr) -> bool {
+    nets.iter().any(|net| net.contains(ip))
+}
+
+/// Resolves the address of the client sending the request.
+///
+/// If the peer is a trusted proxy, the `Forwarded` header, or `X-Forwarded-For` if absent, is
+/// walked from the nearest hop until an address that is not a trusted proxy is found.
+/// Returns `None` if the address is unknown or obfuscated.
+pub fn client_ip(req: &HttpRequest, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let mut ip = req.peer_addr()?.ip().to_canonical();
+
+    if !matches(trusted_proxies, &ip) {
+        return Some(ip);
+    }
+
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("Forwarded")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then_some(value.trim())
+            })
+        })
+        .collect();
+
+    let hops: Vec<&str> = if !forwarded.is_empty() {
+        forwarded
+    } else {
+        req.headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim())
+            .collect()
+    };
+
+    for hop in hops.iter().rev() {
+        ip = parse_hop(hop)?.to_canonical();
+        if !matches(trusted_proxies, &ip) {
+            break;
+        }
+    }
+
+    Some(ip)
+}
+
+/// Parses a hop of `Forwarded` or `X-Forwarded-For`, such as `"[2001:db8::1]:4711"` or `192.0.2.1`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim_matches('"');
+
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    if let Ok(ip) = hop.parse() {
+        return Some(ip);
+    }
+    // IPv4 with a port
+    hop.split(':').next()?.parse().ok()
+}
+
+/// Checks the client address against the deny list, then the allow list if there is one.
+///
+/// Clients with an unknown address are only allowed when there is neither an allow nor a deny list,
+/// as they might be any of the denied ones.
+pub fn is_allowed(ip: Option<IpAddr>, allow: Option<&[Cidr]>, deny: Option<&[Cidr]>) -> bool {
+    match ip {
+        Some(ip) => {
+            !deny.is_some_and(|deny| matches(deny, &ip))
+                && allow.is_none_or(|allow| matches(allow, &ip))
+        }
+        None => allow.is_none() && deny.is_none_or(|deny| deny.is_empty()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use actix_web::test::TestRequest;
+
+    use super::{client_ip, is_allowed, Cidr};
+
+    #[test]
+    fn test_client_ip() {
+        let proxies: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let peer: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        let req = TestRequest::default()
+            .peer_addr(peer)
+            .insert_header(("X-Forwarded-For", "1.1.1.1, 2.2.2.2, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &proxies), ip("2.2.2.2"));
+        assert_eq!(client_ip(&req, &[]), ip("10.0.0.1"));
+
+        let req = TestRequest::default()
+            .peer_addr(peer)
+            .insert_header((
+                "Forwarded",
+                "for=1.1.1.1, for=\"[2001:db8::1]:4711\";proto=https",
+            ))
+            .insert_header(("X-Forwarded-For", "3.3.3.3"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &proxies), ip("2001:db8::1"));
+
+        let req = TestRequest::default()
+            .peer_addr(peer)
+            .insert_header(("Forwarded", "for=_hidden"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &proxies), None);
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let allow: Vec<Cidr> = vec!["192.30.252.0/22".parse().unwrap()];
+        let deny: Vec<Cidr> = vec!["192.30.252.1".parse().unwrap()];
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        assert!(is_allowed(ip("192.30.253.1"), Some(&allow), Some(&deny)));
+        assert!(!is_allowed(ip("192.30.252.1"), Some(&allow), Some(&deny)));
+        assert!(!is_allowed(ip("1.1.1.1"), Some(&allow), None));
+        assert!(is_allowed(ip("1.1.1.1"), None, Some(&deny)));
+        assert!(!is_allowed(None, Some(&allow), None));
+        assert!(!is_allowed(None, None, Some(&deny)));
+        assert!(is_allowed(None, None, None));
+    }
+}
@@ -0,0 +1,635 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, put,
+    web::{self, Data, ServiceConfig},
+    HttpRequest, HttpResponse, Responder,
+};
+use arc_swap::ArcSwap;
+use serde_json::{json, Map, Value};
+use tklog::{error, info, warn};
+use tokio::sync::Mutex;
+
+use crate::{
+    access, auth, check,
+    config::{AuthConfig, NotifyConfig, RuntimeConfig, TokenConfig},
+    interpolate,
+    reload::Reloader,
+};
+
+/// Manages the notifications kept in the admin store, reloading the config on every change.
+pub struct Admin {
+    reloader: Arc<Reloader>,
+    // Each change reads, rewrites and reloads the whole store
+    lock: Mutex<()>,
+}
+
+pub fn routes(cfg: &mut ServiceConfig) {
+    cfg.service(list)
+        .service(show)
+        .service(create)
+        .service(update)
+        .service(remove)
+        .service(enable)
+        .service(disable);
+}
+
+fn error(status: StatusCode, reason: String) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "errors": [reason] }))
+}
+
+fn id_of(notification: &Value) -> Option<&str> {
+    notification.get("id").and_then(|id| id.as_str())
+}
+
+/// The notifications created through the API, and the overrides of the ones defined in the config
+/// files, keyed by their IDs.
+#[derive(Default)]
+struct Store {
+    notifications: Vec<Value>,
+    overrides: Map<String, Value>,
+}
+
+impl Store {
+    fn parse(content: &str) -> Result<Store, String> {
+        let mut value = match serde_json::from_str::<Value>(content) {
+            Ok(Value::Object(obj)) => obj,
+            Ok(_) => return Err("The store must be an object.".to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+        let notifications = match value.remove("notifications") {
+            Some(Value::Array(arr)) => arr,
+            Some(value) => vec![value],
+            None => vec![],
+        };
+        let overrides = match value.remove("overrides") {
+            Some(Value::Object(obj)) => obj,
+            _ => Map::new(),
+        };
+        Ok(Store {
+            notifications,
+            overrides,
+        })
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&json!({
+            "notifications": self.notifications,
+            "overrides": self.overrides,
+        }))
+        .unwrap_or_default()
+    }
+
+    /// Index of the notification in the store, telling apart the ones defined in the config files.
+    fn position(
+        &self,
+        defined: &HashMap<String, NotifyConfig>,
+        id: &str,
+    ) -> Result<usize, (StatusCode, String)> {
+        match self.notifications.iter().position(|n| id_of(n) == Some(id)) {
+            Some(idx) => Ok(idx),
+            None if defined.contains_key(id) => Err((
+                StatusCode::CONFLICT,
+                format!(
+                    "Notification [{}] is defined in the config files. Edit it there instead.",
+                    id
+                ),
+            )),
+            None => Err((
+                StatusCode::NOT_FOUND,
+                format!("No notification found with ID [{}].", id),
+            )),
+        }
+    }
+
+    /// Enables or disables the notification, overriding `enabled` of the ones defined in the
+    /// config files. Returns the notification if it's kept in the store.
+    fn set_enabled(
+        &mut self,
+        defined: &HashMap<String, NotifyConfig>,
+        id: &str,
+        enabled: bool,
+    ) -> Result<Option<Value>, (StatusCode, String)> {
+        match self.position(defined, id) {
+            Ok(idx) => {
+                self.notifications[idx]["enabled"] = json!(enabled);
+                Ok(Some(self.notifications[idx].clone()))
+            }
+            Err((StatusCode::CONFLICT, _)) => {
+                self.overrides
+                    .insert(id.to_string(), json!({ "enabled": enabled }));
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Reads the store, returning its content, which is `None` if it doesn't exist yet, and what it
+/// keeps.
+fn read_store(path: &Path) -> Result<(Option<String>, Store), String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((None, Store::default())),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let store =
+        Store::parse(&content).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    Ok((Some(content), store))
+}
+
+/// Replaces the content of the store, or removes it for `None`, through a temporary file so that
+/// it's never read half written.
+fn write_store(path: &Path, content: Option<&str>) -> Result<(), String> {
+    let res = match content {
+        Some(content) => {
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            fs::write(&tmp, content).and_then(|_| fs::rename(&tmp, path))
+        }
+        None => fs::remove_file(path).or_else(|e| match e.kind() {
+            ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        }),
+    };
+    res.map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Parses the body as a notification, filling in the ID if given.
+fn parse_notification(body: &str, id: Option<&str>) -> Result<Value, (StatusCode, String)> {
+    let mut notification = match serde_json::from_str::<Value>(body) {
+        Ok(value) if value.is_object() => value,
+        Ok(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "The notification must be an object.".to_string(),
+            ))
+        }
+        Err(e) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Failed to parse body as json: {}", e),
+            ))
+        }
+    };
+
+    match (id_of(&notification), id) {
+        (None, Some(id)) => notification["id"] = json!(id),
+        (None, None) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "The notification has no ID.".to_string(),
+            ))
+        }
+        (Some(given), Some(id)) if given != id => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("The notification has a different ID [{}].", given),
+            ))
+        }
+        _ => {}
+    }
+
+    // The API must not write to any file but the store
+    if notification.pointer("/dedup/persist").is_some() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "dedup.persist can't be set through the admin API.".to_string(),
+        ));
+    }
+    // Nor read any, and an ignored `token_file` would leave the notification open
+    if let Some(path) = check::unknown_fields(&notification).first() {
+        let reason = if path.ends_with("_file") {
+            format!(
+                "Unknown field {}. Files are not read through the admin API, give the secret itself.",
+                path
+            )
+        } else {
+            format!("Unknown field {}.", path)
+        };
+        return Err((StatusCode::BAD_REQUEST, reason));
+    }
+    Ok(notification)
+}
+
+/// The notification with its secrets redacted, as the API must not reveal them.
+fn redacted(notification: &Value) -> Value {
+    let mut notification = notification.clone();
+    interpolate::redact(&mut notification, "notifications[]");
+    notification
+}
+
+impl Admin {
+    pub fn new(reloader: Arc<Reloader>) -> Admin {
+        Admin {
+            reloader,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Checks the client may manage the notifications, returning the store, or the status and the
+    /// reason rejecting it.
+    async fn authorize(
+        &self,
+        req: &HttpRequest,
+        conf: &RuntimeConfig,
+    ) -> Result<PathBuf, (StatusCode, &'static str)> {
+        let client_ip = access::client_ip(
+            req,
+            conf.server.trusted_proxies.as_deref().unwrap_or_default(),
+        );
+        let addr = client_ip.map_or("Unknown Addr".to_string(), |ip| ip.to_string());
+        info!(format!("{} -> {} {}", addr, req.method(), req.path()));
+
+        if !access::is_allowed(
+            client_ip,
+            conf.server.allow.as_deref(),
+            conf.server.deny.as_deref(),
+        ) {
+            warn!(format!(
+                "{} is not allowed to access the server. Rejected.",
+                addr
+            ));
+            return Err((StatusCode::FORBIDDEN, "Access Denied."));
+        }
+
+        let Some(admin) = &conf.admin else {
+            warn!("Admin API is not configured. Rejected.");
+            return Err((StatusCode::NOT_FOUND, "The admin API is disabled."));
+        };
+        let token = TokenConfig {
+            token: admin.token.clone(),
+            label: None,
+            not_before: None,
+            expires_at: None,
+        };
+        if let Err(reason) = auth::authorize(&AuthConfig::Bearer, &[token], req, "").await {
+            warn!(format!("{} Rejected.", reason));
+            return Err((StatusCode::UNAUTHORIZED, "Permission Denied."));
+        }
+
+        Ok(admin.store(self.reloader.dir()))
+    }
+
+    /// The notifications of the current config with their secrets redacted, telling whether each
+    /// is kept in the store.
+    fn entries(&self, store: &Path) -> Result<Vec<Value>, String> {
+        let (_, store) = read_store(store)?;
+        let raw = self.reloader.raw();
+        Ok(raw
+            .get("notifications")
+            .and_then(|n| n.as_array())
+            .into_iter()
+            .flatten()
+            .map(|n| {
+                json!({
+                    "managed": store.notifications.iter().any(|m| id_of(m) == id_of(n)),
+                    "notification": redacted(n),
+                })
+            })
+            .collect())
+    }
+
+    /// Applies the change to the notifications in the store, keeping it only if the config stays
+    /// valid, exactly as it's checked on startup.
+    async fn change(
+        &self,
+        store: &Path,
+        status: StatusCode,
+        apply: impl FnOnce(&mut Store) -> Result<Option<Value>, (StatusCode, String)>,
+    ) -> HttpResponse {
+        let _guard = self.lock.lock().await;
+
+        let (old, mut kept) = match read_store(store) {
+            Ok(res) => res,
+            Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        };
+        let notification = match apply(&mut kept) {
+            Ok(notification) => notification,
+            Err((status, reason)) => return error(status, reason),
+        };
+
+        if let Err(e) = write_store(store, Some(&kept.to_json())) {
+            error!(e.clone());
+            return error(StatusCode::INTERNAL_SERVER_ERROR, e);
+        }
+
+        match self.reloader.reload() {
+            Ok(report) => {
+                let mut res = json!({ "warnings": report.warnings });
+                if let Some(notification) = notification {
+                    res["notification"] = redacted(&notification);
+                }
+                HttpResponse::build(status).json(res)
+            }
+            Err(report) => {
+                if let Err(e) = write_store(store, old.as_deref()) {
+                    error!(format!("{}. The store is left invalid.", e));
+                }
+                HttpResponse::UnprocessableEntity().json(json!({
+                    "errors": report.errors,
+                    "warnings": report.warnings,
+                }))
+            }
+        }
+    }
+
+    async fn set_enabled(
+        &self,
+        req: &HttpRequest,
+        conf: &RuntimeConfig,
+        id: &str,
+        enabled: bool,
+    ) -> HttpResponse {
+        let store = match self.authorize(req, conf).await {
+            Ok(store) => store,
+            Err((status, reason)) => return error(status, reason.to_string()),
+        };
+        let raw = self.reloader.raw();
+        self.change(&store, StatusCode::OK, |kept| {
+            let notification = kept.set_enabled(&conf.notifications, id, enabled)?;
+            // The ones of the config files are responded as overridden
+            Ok(notification.or_else(|| {
+                let mut notification = raw
+                    .get("notifications")?
+                    .as_array()?
+                    .iter()
+                    .find(|n| id_of(n) == Some(id))?
+                    .clone();
+                notification["enabled"] = json!(enabled);
+                Some(notification)
+            }))
+        })
+        .await
+    }
+}
+
+/// Lists the notifications, telling whether each is managed by the API.
+#[get("/admin/notifications")]
+async fn list(
+    req: HttpRequest,
+    admin: Data<Admin>,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+) -> impl Responder {
+    let conf = conf.load_full();
+    let store = match admin.authorize(&req, &conf).await {
+        Ok(store) => store,
+        Err((status, reason)) => return error(status, reason.to_string()),
+    };
+    match admin.entries(&store) {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+#[get("/admin/notifications/{id}")]
+async fn show(
+    req: HttpRequest,
+    id: web::Path<String>,
+    admin: Data<Admin>,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+) -> impl Responder {
+    let conf = conf.load_full();
+    let store = match admin.authorize(&req, &conf).await {
+        Ok(store) => store,
+        Err((status, reason)) => return error(status, reason.to_string()),
+    };
+    let entries = match admin.entries(&store) {
+        Ok(entries) => entries,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    match entries
+        .into_iter()
+        .find(|e| id_of(&e["notification"]) == Some(id.as_str()))
+    {
+        Some(entry) => HttpResponse::Ok().json(entry),
+        None => error(
+            StatusCode::NOT_FOUND,
+            format!("No notification found with ID [{}].", id),
+        ),
+    }
+}
+
+#[post("/admin/notifications")]
+async fn create(
+    req: HttpRequest,
+    body: String,
+    admin: Data<Admin>,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+) -> impl Responder {
+    let conf = conf.load_full();
+    let store = match admin.authorize(&req, &conf).await {
+        Ok(store) => store,
+        Err((status, reason)) => return error(status, reason.to_string()),
+    };
+    let notification = match parse_notification(&body, None) {
+        Ok(notification) => notification,
+        Err((status, reason)) => return error(status, reason),
+    };
+
+    admin
+        .change(&store, StatusCode::CREATED, |kept| {
+            let id = id_of(&notification).unwrap_or_default();
+            if conf.notifications.contains_key(id)
+                || kept.notifications.iter().any(|n| id_of(n) == Some(id))
+            {
+                return Err((
+                    StatusCode::CONFLICT,
+                    format!("Notification [{}] already exists.", id),
+                ));
+            }
+            // Left behind by a notification removed from the config files
+            kept.overrides.remove(id);
+            kept.notifications.push(notification.clone());
+            Ok(Some(notification))
+        })
+        .await
+}
+
+/// Replaces the notification, whose ID can't be changed.
+#[put("/admin/notifications/{id}")]
+async fn update(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: String,
+    admin: Data<Admin>,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+) -> impl Responder {
+    let conf = conf.load_full();
+    let store = match admin.authorize(&req, &conf).await {
+        Ok(store) => store,
+        Err((status, reason)) => return error(status, reason.to_string()),
+    };
+    let notification = match parse_notification(&body, Some(&id)) {
+        Ok(notification) => notification,
+        Err((status, reason)) => return error(status, reason),
+    };
+
+    admin
+        .change(&store, StatusCode::OK, |kept| {
+            let idx = kept.position(&conf.notifications, &id)?;
+            kept.notifications[idx] = notification.clone();
+            Ok(Some(notification))
+        })
+        .await
+}
+
+#[delete("/admin/notifications/{id}")]
+async fn remove(
+    req: HttpRequest,
+    id: web::Path<String>,
+    admin: Data<Admin>,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+) -> impl Responder {
+    let conf = conf.load_full();
+    let store = match admin.authorize(&req, &conf).await {
+        Ok(store) => store,
+        Err((status, reason)) => return error(status, reason.to_string()),
+    };
+
+    admin
+        .change(&store, StatusCode::OK, |kept| {
+            let idx = kept.position(&conf.notifications, &id)?;
+            kept.notifications.remove(idx);
+            Ok(None)
+        })
+        .await
+}
+
+#[post("/admin/notifications/{id}/enable")]
+async fn enable(
+    req: HttpRequest,
+    id: web::Path<String>,
+    admin: Data<Admin>,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+) -> impl Responder {
+    admin.set_enabled(&req, &conf.load_full(), &id, true).await
+}
+
+#[post("/admin/notifications/{id}/disable")]
+async fn disable(
+    req: HttpRequest,
+    id: web::Path<String>,
+    admin: Data<Admin>,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+) -> impl Responder {
+    admin.set_enabled(&req, &conf.load_full(), &id, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use actix_web::http::StatusCode;
+    use serde_json::json;
+
+    use super::{parse_notification, Store};
+    use crate::config::NotifyConfig;
+
+    #[test]
+    fn test_parse_notification() {
+        assert_eq!(
+            parse_notification(r#"{ "groups": ["1"] }"#, Some("a")).unwrap(),
+            json!({ "id": "a", "groups": ["1"] })
+        );
+        assert_eq!(
+            parse_notification(r#"{ "id": "b" }"#, None).unwrap(),
+            json!({ "id": "b" })
+        );
+        assert_eq!(
+            parse_notification(r#"{ "id": "b" }"#, Some("a")).unwrap_err(),
+            (
+                StatusCode::BAD_REQUEST,
+                "The notification has a different ID [b].".to_string()
+            )
+        );
+        assert_eq!(
+            parse_notification("{}", None).unwrap_err().1,
+            "The notification has no ID."
+        );
+        assert_eq!(
+            parse_notification("[]", None).unwrap_err().1,
+            "The notification must be an object."
+        );
+        assert_eq!(
+            parse_notification(
+                r#"{ "dedup": { "window": 60, "persist": "/etc/passwd" } }"#,
+                Some("a")
+            )
+            .unwrap_err()
+            .0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn test_parse_secret_files() {
+        assert_eq!(
+            parse_notification(r#"{ "token_file": "/run/secrets/t" }"#, Some("s")).unwrap_err(),
+            (
+                StatusCode::BAD_REQUEST,
+                "Unknown field token_file. Files are not read through the admin API, give the secret itself."
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            parse_notification(
+                r#"{ "tokens": [{ "token": "a" }, { "token_file": "/run/secrets/t" }] }"#,
+                Some("s")
+            )
+            .unwrap_err(),
+            (
+                StatusCode::BAD_REQUEST,
+                "Unknown field tokens.?.1.token_file. Files are not read through the admin API, give the secret itself."
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            parse_notification(r#"{ "groups": ["1"], "mesage": "hi" }"#, Some("s"))
+                .unwrap_err()
+                .1,
+            "Unknown field mesage."
+        );
+        assert!(parse_notification(r#"{ "tokens": [{ "token": "a" }] }"#, Some("s")).is_ok());
+    }
+
+    #[test]
+    fn test_set_enabled() {
+        let defined: HashMap<String, NotifyConfig> = [(
+            "a".to_string(),
+            serde_json::from_value(json!({ "id": "a", "groups": ["1"] })).unwrap(),
+        )]
+        .into();
+        let mut store = Store::parse(r#"{ "notifications": [{ "id": "b" }] }"#).unwrap();
+
+        // The ones of the config files are disabled by overriding them
+        assert_eq!(store.set_enabled(&defined, "a", false), Ok(None));
+        assert_eq!(
+            store.set_enabled(&defined, "b", false),
+            Ok(Some(json!({ "id": "b", "enabled": false })))
+        );
+        assert_eq!(
+            store.set_enabled(&defined, "c", false).unwrap_err().0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            store.position(&defined, "a").unwrap_err().0,
+            StatusCode::CONFLICT
+        );
+
+        let store = Store::parse(&store.to_json()).unwrap();
+        assert_eq!(store.overrides["a"], json!({ "enabled": false }));
+        assert_eq!(
+            store.notifications,
+            vec![json!({ "id": "b", "enabled": false })]
+        );
+    }
+}
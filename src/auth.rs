@@ -0,0 +1,485 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    dev::Payload,
+    web::{self, Query},
+    FromRequest, HttpRequest,
+};
+use actix_web_httpauth::extractors::{basic::BasicAuth, bearer::BearerAuth};
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use strfmt::strfmt;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::{AuthConfig, HmacAlgorithm, HmacConfig, SignatureEncoding, TokenConfig},
+    tls::ClientIdentity,
+};
+
+/// Checks whether the request is allowed to trigger a notification protected by the tokens.
+///
+/// Returns the label of the token authenticating the request, or the reason of the rejection.
+/// Notifications without any token are open to every request, which is authenticated by no token,
+/// except for mTLS ones which always require a verified client certificate.
+///
+/// Argon2 and bcrypt hashes take hundreds of milliseconds each, so they're verified on the
+/// blocking thread pool, keeping the workers serving the other requests.
+pub async fn authorize(
+    method: &AuthConfig,
+    tokens: &[TokenConfig],
+    req: &HttpRequest,
+    body: &str,
+) -> Result<Option<String>, String> {
+    let identities = match method {
+        AuthConfig::Mtls => Some(
+            req.conn_data::<ClientIdentity>()
+                .ok_or("No verified client certificate provided.")?
+                .0
+                .clone(),
+        ),
+        _ => None,
+    };
+
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let now = Utc::now();
+    let provided = match method {
+        AuthConfig::Hmac(hmac) => {
+            let mut reason = "All tokens are expired or not yet valid.".to_string();
+            for token in tokens.iter().filter(|t| t.is_valid_at(now)) {
+                match verify_signature(hmac, &token.token, req, body) {
+                    Ok(()) => return Ok(token.label.clone()),
+                    Err(err) => reason = err,
+                }
+            }
+            return Err(reason);
+        }
+        AuthConfig::Mtls => identities.unwrap_or_default(),
+        _ => vec![credential(method, req)?],
+    };
+
+    if tokens.iter().any(|t| is_slow_hash(&t.token)) {
+        let tokens = tokens.to_vec();
+        return web::block(move || find_token(&tokens, &provided, now))
+            .await
+            .map_err(|e| format!("Failed to verify the token: {}", e))?;
+    }
+    find_token(tokens, &provided, now)
+}
+
+/// Finds the token matching any of the provided ones, telling apart the ones not valid at `now`.
+fn find_token(
+    tokens: &[TokenConfig],
+    provided: &[String],
+    now: DateTime<Utc>,
+) -> Result<Option<String>, String> {
+    let (valid, invalid): (Vec<&TokenConfig>, Vec<&TokenConfig>) =
+        tokens.iter().partition(|t| t.is_valid_at(now));
+
+    for provided in provided {
+        if let Some(token) = valid.iter().find(|t| verify_token(&t.token, provided)) {
+            return Ok(token.label.clone());
+        }
+    }
+    for provided in provided {
+        if let Some(token) = invalid.iter().find(|t| verify_token(&t.token, provided)) {
+            return Err(format!(
+                "Token [{}] is expired or not yet valid.",
+                token.label.clone().unwrap_or_default()
+            ));
+        }
+    }
+    Err("Wrong token provided.".to_string())
+}
+
+/// Reads the token presented by the request, for the methods sending the token itself.
+fn credential(method: &AuthConfig, req: &HttpRequest) -> Result<String, String> {
+    match method {
+        AuthConfig::Bearer => BearerAuth::from_request(req, &mut Payload::None)
+            .into_inner()
+            .map(|auth| auth.token().to_string())
+            .map_err(|_| "No token provided.".to_string()),
+        AuthConfig::Basic { username } => {
+            let auth = BasicAuth::from_request(req, &mut Payload::None)
+                .into_inner()
+                .map_err(|_| "No credentials provided.".to_string())?;
+            if username.as_ref().is_some_and(|u| u != auth.user_id()) {
+                return Err("Wrong username provided.".to_string());
+            }
+            auth.password()
+                .map(|p| p.to_string())
+                .ok_or("No password provided.".to_string())
+        }
+        AuthConfig::Header { name, prefix } => {
+            let value = header(req, name)?;
+            match prefix {
+                Some(prefix) => value
+                    .strip_prefix(prefix.as_str())
+                    .map(|v| v.to_string())
+                    .ok_or(format!("{} header does not start with {}.", name, prefix)),
+                None => Ok(value.to_string()),
+            }
+        }
+        AuthConfig::Query { param } => {
+            let param = param.as_deref().unwrap_or("token");
+            Query::<HashMap<String, String>>::from_query(req.query_string())
+                .ok()
+                .and_then(|query| query.get(param).cloned())
+                .ok_or(format!("No {} query parameter provided.", param))
+        }
+        AuthConfig::Hmac(_) | AuthConfig::Mtls => {
+            Err("No token is sent by the method.".to_string())
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum HashAlgorithm {
+    Argon2,
+    Bcrypt,
+    Sha256,
+}
+
+/// Hashes the token into a string that can be stored as the `token` of a notification.
+pub fn hash_token(token: &str, algorithm: HashAlgorithm) -> String {
+    match algorithm {
+        HashAlgorithm::Argon2 => Argon2::default()
+            .hash_password(token.as_bytes(), &SaltString::generate(&mut OsRng))
+            .expect("Argon2 accepts tokens of any length")
+            .to_string(),
+        HashAlgorithm::Bcrypt => {
+            bcrypt::hash(token, bcrypt::DEFAULT_COST).expect("Bcrypt accepts the default cost")
+        }
+        HashAlgorithm::Sha256 => {
+            let salt = SaltString::generate(&mut OsRng);
+            let digest = Sha256::digest(format!("{}{}", salt.as_str(), token));
+            format!("$sha256${}${}", salt.as_str(), hex::encode(digest))
+        }
+    }
+}
+
+/// Whether the stored token is a hash generated by [`hash_token`] rather than the token itself.
+pub fn is_hashed(stored: &str) -> bool {
+    is_slow_hash(stored) || stored.starts_with("$sha256$")
+}
+
+fn is_bcrypt(stored: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$"]
+        .iter()
+        .any(|p| stored.starts_with(p))
+}
+
+/// Whether the stored token is an Argon2 or bcrypt hash, which is deliberately slow to verify.
+fn is_slow_hash(stored: &str) -> bool {
+    stored.starts_with("$argon2") || is_bcrypt(stored)
+}
+
+/// Compares the provided token with the stored one, which can either be the token itself or its hash.
+///
+/// Plaintext and sha256 tokens are compared in constant time, Argon2 and bcrypt verify in constant time by design.
+pub fn verify_token(stored: &str, provided: &str) -> bool {
+    if stored.starts_with("$argon2") {
+        return PasswordHash::new(stored)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(provided.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false);
+    }
+
+    if is_bcrypt(stored) {
+        return bcrypt::verify(provided, stored).unwrap_or(false);
+    }
+
+    if let Some(hash) = stored.strip_prefix("$sha256$") {
+        if let Some((salt, digest)) = hash.split_once('$') {
+            let provided = hex::encode(Sha256::digest(format!("{}{}", salt, provided)));
+            return provided.as_bytes().ct_eq(digest.as_bytes()).into();
+        }
+        return false;
+    }
+
+    stored.as_bytes().ct_eq(provided.as_bytes()).into()
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Result<&'a str, String> {
+    req.headers()
+        .get(name)
+        .ok_or(format!("No {} header provided.", name))?
+        .to_str()
+        .map_err(|_| format!("Malformed {} header.", name))
+}
+
+fn verify_signature(
+    cfg: &HmacConfig,
+    secret: &str,
+    req: &HttpRequest,
+    body: &str,
+) -> Result<(), String> {
+    if is_hashed(secret) {
+        return Err("HMAC signatures can't be verified with a hashed token.".to_string());
+    }
+
+    let signature = header(req, &cfg.header)?;
+    let signature = match &cfg.prefix {
+        Some(prefix) => signature
+            .strip_prefix(prefix.as_str())
+            .ok_or(format!("Signature does not start with {}.", prefix))?,
+        None => signature,
+    };
+    let signature = match cfg.encoding.unwrap_or(SignatureEncoding::Hex) {
+        SignatureEncoding::Hex => hex::decode(signature).map_err(|e| e.to_string()),
+        SignatureEncoding::Base64 => BASE64_STANDARD.decode(signature).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("Failed to decode signature: {}", e))?;
+
+    let mut vars = HashMap::new();
+    vars.insert("body".to_string(), body.to_string());
+
+    if let Some(timestamp_header) = &cfg.timestamp_header {
+        let timestamp = header(req, timestamp_header)?;
+        let sent: u64 = timestamp
+            .parse()
+            .map_err(|_| format!("Unrecognized timestamp: {}", timestamp))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(sent) > cfg.tolerance.unwrap_or(300) {
+            return Err(format!(
+                "Timestamp {} is out of the tolerance window.",
+                sent
+            ));
+        }
+        vars.insert("timestamp".to_string(), timestamp.to_string());
+    }
+
+    let payload = match &cfg.payload {
+        Some(template) => {
+            strfmt(template, &vars).map_err(|e| format!("Failed to build signed payload: {}", e))?
+        }
+        None => body.to_string(),
+    };
+
+    let valid = match cfg.algorithm.unwrap_or(HmacAlgorithm::Sha256) {
+        HmacAlgorithm::Sha1 => verify_mac::<Hmac<Sha1>>(secret, &payload, &signature),
+        HmacAlgorithm::Sha256 => verify_mac::<Hmac<Sha256>>(secret, &payload, &signature),
+        HmacAlgorithm::Sha512 => verify_mac::<Hmac<Sha512>>(secret, &payload, &signature),
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err("Wrong signature provided.".to_string())
+    }
+}
+
+fn verify_mac<M: Mac + hmac::digest::KeyInit>(
+    secret: &str,
+    payload: &str,
+    signature: &[u8],
+) -> bool {
+    let mut mac =
+        <M as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    mac.verify_slice(signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use actix_web::test::TestRequest;
+    use chrono::{Duration, Utc};
+    use futures::executor::block_on;
+
+    use super::{authorize, hash_token, is_hashed, verify_token, HashAlgorithm};
+    use crate::config::{AuthConfig, HmacConfig, TokenConfig};
+
+    fn tokens(token: &str) -> Vec<TokenConfig> {
+        vec![TokenConfig {
+            token: token.to_string(),
+            label: Some("test".to_string()),
+            not_before: None,
+            expires_at: None,
+        }]
+    }
+
+    #[test]
+    fn test_hmac() {
+        let secret = "It's a Secret to Everybody";
+        let body = "Hello, World!";
+        let method = AuthConfig::Hmac(HmacConfig {
+            header: "X-Hub-Signature-256".to_string(),
+            algorithm: None,
+            prefix: Some("sha256=".to_string()),
+            encoding: None,
+            timestamp_header: None,
+            tolerance: None,
+            payload: None,
+        });
+
+        let req = TestRequest::default()
+            .insert_header((
+                "X-Hub-Signature-256",
+                "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+            ))
+            .to_http_request();
+        let tokens = tokens(secret);
+        assert!(block_on(authorize(&method, &tokens, &req, body)).is_ok());
+        assert!(block_on(authorize(&method, &tokens, &req, "Hello, World?")).is_err());
+
+        let req = TestRequest::default().to_http_request();
+        assert!(block_on(authorize(&method, &tokens, &req, body)).is_err());
+        assert!(block_on(authorize(&method, &[], &req, body)).is_ok());
+    }
+
+    #[test]
+    fn test_hmac_timestamp() {
+        let method = AuthConfig::Hmac(HmacConfig {
+            header: "X-Signature".to_string(),
+            algorithm: None,
+            prefix: None,
+            encoding: None,
+            timestamp_header: Some("X-Timestamp".to_string()),
+            tolerance: Some(60),
+            payload: Some("{timestamp}.{body}".to_string()),
+        });
+
+        let sign = |timestamp: u64| {
+            use hmac::{Hmac, Mac};
+            let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+            mac.update(format!("{}.body", timestamp).as_bytes());
+            let req = TestRequest::default()
+                .insert_header(("X-Signature", hex::encode(mac.finalize().into_bytes())))
+                .insert_header(("X-Timestamp", timestamp.to_string()))
+                .to_http_request();
+            block_on(authorize(&method, &tokens("secret"), &req, "body"))
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(sign(now).is_ok());
+        assert!(sign(now - 3600).is_err());
+    }
+
+    #[test]
+    fn test_hashed_token() {
+        for algorithm in [
+            HashAlgorithm::Argon2,
+            HashAlgorithm::Bcrypt,
+            HashAlgorithm::Sha256,
+        ] {
+            let hash = hash_token("token", algorithm);
+            assert!(verify_token(&hash, "token"));
+            assert!(!verify_token(&hash, "nekot"));
+        }
+
+        assert!(verify_token("token", "token"));
+        assert!(!verify_token("token", "token2"));
+
+        // Only the prefixes of bcrypt are taken as its hashes
+        assert!(!is_hashed("$2secret"));
+        assert!(verify_token("$2secret", "$2secret"));
+    }
+
+    #[actix_web::test]
+    async fn test_hashed_authorize() {
+        // Verified on the blocking thread pool
+        let tokens = tokens(&hash_token("token", HashAlgorithm::Bcrypt));
+        let check = async |token: &str| {
+            let req = TestRequest::default()
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_http_request();
+            authorize(&AuthConfig::Bearer, &tokens, &req, "").await
+        };
+
+        assert_eq!(check("token").await, Ok(Some("test".to_string())));
+        assert!(check("nekot").await.is_err());
+    }
+
+    #[test]
+    fn test_token_rotation() {
+        let now = Utc::now();
+        let tokens = vec![
+            TokenConfig {
+                token: "old".to_string(),
+                label: Some("old".to_string()),
+                not_before: None,
+                expires_at: Some(now - Duration::days(1)),
+            },
+            TokenConfig {
+                token: "new".to_string(),
+                label: Some("new".to_string()),
+                not_before: Some(now - Duration::days(2)),
+                expires_at: None,
+            },
+        ];
+
+        let check = |token: &str| {
+            let req = TestRequest::default()
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_http_request();
+            block_on(authorize(&AuthConfig::Bearer, &tokens, &req, ""))
+        };
+
+        assert_eq!(check("new"), Ok(Some("new".to_string())));
+        assert!(check("old").is_err());
+        assert!(check("other").is_err());
+    }
+
+    #[test]
+    fn test_token_methods() {
+        let tokens = tokens("secret");
+        let check = |method: AuthConfig, req: TestRequest| {
+            block_on(authorize(&method, &tokens, &req.to_http_request(), "")).is_ok()
+        };
+
+        assert!(check(
+            AuthConfig::Basic {
+                username: Some("bot".to_string())
+            },
+            TestRequest::default().insert_header(("Authorization", "Basic Ym90OnNlY3JldA=="))
+        ));
+        assert!(!check(
+            AuthConfig::Basic {
+                username: Some("admin".to_string())
+            },
+            TestRequest::default().insert_header(("Authorization", "Basic Ym90OnNlY3JldA=="))
+        ));
+        assert!(check(
+            AuthConfig::Header {
+                name: "X-Gitlab-Token".to_string(),
+                prefix: None
+            },
+            TestRequest::default().insert_header(("X-Gitlab-Token", "secret"))
+        ));
+        assert!(check(
+            AuthConfig::Query { param: None },
+            TestRequest::with_uri("/notify-test?token=secret")
+        ));
+        assert!(!check(
+            AuthConfig::Query {
+                param: Some("key".to_string())
+            },
+            TestRequest::with_uri("/notify-test?token=secret")
+        ));
+        assert!(!check(AuthConfig::Mtls, TestRequest::default()));
+    }
+}
@@ -0,0 +1,877 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use tklog::{error, warn};
+
+use crate::{
+    auth,
+    config::{
+        AuthConfig, Config, ContentExtractConfig, Format, NotifyConfig, NotifyOverride, Overrides,
+    },
+    interpolate,
+    preset::{self, Preset, Renderer},
+    schedule,
+    service::validate_path,
+};
+
+/// Problems found in the config. Errors prevent it from being used, while warnings don't.
+#[derive(Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Prints the problems to the console, for when the logger isn't set up.
+    pub fn print(&self) {
+        for w in &self.warnings {
+            println!("\x1b[33mWarning: {}\x1b[0m", w);
+        }
+        for e in &self.errors {
+            println!("\x1b[31mError: {}\x1b[0m", e);
+        }
+    }
+
+    /// Takes the problems of another report, prefixed with the file they are found in.
+    fn merge(&mut self, other: Report, file: Option<&str>) {
+        let prefix = |p: String| match file {
+            Some(file) => format!("{}: {}", file, p),
+            None => p,
+        };
+        self.errors.extend(other.errors.into_iter().map(prefix));
+        self.warnings.extend(other.warnings.into_iter().map(prefix));
+    }
+
+    pub fn log(&self) {
+        for w in &self.warnings {
+            warn!(w);
+        }
+        for e in &self.errors {
+            error!(e);
+        }
+    }
+}
+
+/// Parses and checks the config, along with its raw value with the variables and files resolved.
+///
+/// The notifications of the files included are merged, with the patterns relative to `dir`.
+pub fn check(
+    content: &str,
+    format: Format,
+    dir: &Path,
+    overrides: &Overrides,
+) -> (Option<(Config, Value)>, Report) {
+    let mut report = Report::default();
+    let Some((mut conf, mut value)) = parse(content, format, overrides, &mut report) else {
+        return (None, report);
+    };
+    let sources = include(&mut conf, &mut value, dir, &mut report);
+    validate(&conf, &sources, &mut report);
+    (Some((conf, value)), report)
+}
+
+/// Files matching the include pattern, relative to `dir`, in alphabetical order.
+pub fn included(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let pattern = dir.join(pattern);
+    let paths = glob::glob(&pattern.to_string_lossy())
+        .map_err(|e| format!("Invalid include pattern [{}]: {}", pattern.display(), e))?;
+    Ok(paths.flatten().filter(|p| p.is_file()).collect())
+}
+
+/// Merges the notifications of the files included and the admin store into the config and its
+/// raw value, then applies the overrides of the store.
+///
+/// Returns the file defining each notification, which is `None` for the config file itself.
+fn include(
+    conf: &mut Config,
+    value: &mut Value,
+    dir: &Path,
+    report: &mut Report,
+) -> Vec<Option<String>> {
+    let mut sources = vec![None; conf.notifications.len()];
+
+    let mut files = vec![];
+    for pattern in conf.include.iter().flatten() {
+        match included(dir, pattern) {
+            Ok(paths) => files.extend(paths),
+            Err(e) => report.errors.push(e),
+        }
+    }
+
+    for path in files {
+        let file = path.display().to_string();
+        let mut problems = Report::default();
+        if let Some((notifications, raw)) = parse_notifications(&path, &mut problems) {
+            merge(conf, value, &mut sources, &file, notifications, raw);
+        }
+        report.merge(problems, Some(&file));
+    }
+
+    if let Some(store) = conf.admin.as_ref().map(|a| a.store(dir)) {
+        if store.exists() {
+            let file = store.display().to_string();
+            let mut problems = Report::default();
+            if let Some(((notifications, raw), overrides)) = parse_store(&store, &mut problems) {
+                merge(conf, value, &mut sources, &file, notifications, raw);
+                apply_overrides(conf, value, overrides, &mut problems);
+            }
+            report.merge(problems, Some(&file));
+        }
+    }
+
+    sources
+}
+
+fn merge(
+    conf: &mut Config,
+    value: &mut Value,
+    sources: &mut Vec<Option<String>>,
+    file: &str,
+    notifications: Vec<NotifyConfig>,
+    raw: Vec<Value>,
+) {
+    sources.extend(notifications.iter().map(|_| Some(file.to_string())));
+    conf.notifications.extend(notifications);
+    if let Some(arr) = value
+        .get_mut("notifications")
+        .and_then(|n| n.as_array_mut())
+    {
+        arr.extend(raw);
+    }
+}
+
+fn apply_overrides(
+    conf: &mut Config,
+    value: &mut Value,
+    overrides: HashMap<String, NotifyOverride>,
+    report: &mut Report,
+) {
+    for (id, o) in overrides {
+        let Some(notification) = conf.notifications.iter_mut().find(|n| n.id == id) else {
+            report.warnings.push(format!(
+                "Override of notification [{}], which doesn't exist, is ignored.",
+                id
+            ));
+            continue;
+        };
+        if let Some(enabled) = o.enabled {
+            notification.enabled = Some(enabled);
+            if let Some(raw) = value
+                .get_mut("notifications")
+                .and_then(|n| n.as_array_mut())
+                .and_then(|arr| {
+                    arr.iter_mut()
+                        .find(|n| n.get("id").and_then(|i| i.as_str()) == Some(id.as_str()))
+                })
+            {
+                raw["enabled"] = Value::Bool(enabled);
+            }
+        }
+    }
+}
+
+/// The notifications parsed, along with their raw values.
+type Notifications = (Vec<NotifyConfig>, Vec<Value>);
+
+fn read_value(path: &Path, format: Format, report: &mut Report) -> Option<Value> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            report.errors.push(format!("Failed to read file: {}", e));
+            return None;
+        }
+    };
+    match format.value(&content) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            report.errors.push(e);
+            None
+        }
+    }
+}
+
+/// Parses an included file, defining either one notification or an array of them.
+fn parse_notifications(path: &Path, report: &mut Report) -> Option<Notifications> {
+    let mut value = read_value(path, Format::of(path), report)?;
+    let at = if value.is_array() {
+        "notifications"
+    } else {
+        "notifications[]"
+    };
+    if let Err(errors) = interpolate::resolve(&mut value, at) {
+        report.errors.extend(errors);
+        return None;
+    }
+    notifications_of(value, false, report)
+}
+
+/// Parses the admin store, holding the notifications created through the API and the overrides
+/// of the other ones.
+///
+/// It's written by the API, whose requests must not read the environment or other files, so
+/// nothing is resolved.
+fn parse_store(
+    path: &Path,
+    report: &mut Report,
+) -> Option<(Notifications, HashMap<String, NotifyOverride>)> {
+    let mut value = read_value(path, Format::Json, report)?;
+    let Some(store) = value.as_object_mut() else {
+        report
+            .errors
+            .push("The admin store must be an object.".to_string());
+        return None;
+    };
+
+    let notifications = store
+        .remove("notifications")
+        .unwrap_or(Value::Array(vec![]));
+    let overrides = store
+        .remove("overrides")
+        .unwrap_or(Value::Object(Default::default()));
+    for key in store.keys() {
+        report
+            .warnings
+            .push(format!("Unknown field {}, which is ignored.", key));
+    }
+
+    let (notifications, raw) = notifications_of(notifications, true, report)?;
+    let mut unknown = vec![];
+    match deserialize(overrides, &mut unknown) {
+        Ok(overrides) => {
+            for path in unknown {
+                report.warnings.push(format!(
+                    "Unknown field overrides.{}, which is ignored.",
+                    path
+                ));
+            }
+            Some(((notifications, raw), overrides))
+        }
+        Err((path, e)) => {
+            report
+                .errors
+                .push(located(format!("overrides.{}", path), e));
+            None
+        }
+    }
+}
+
+/// Deserializes the notifications. Unknown fields are errors if `strict`, as in the admin store,
+/// where `_file` fields would be silently ignored otherwise.
+fn notifications_of(value: Value, strict: bool, report: &mut Report) -> Option<Notifications> {
+    let raw = match value {
+        Value::Array(arr) => arr,
+        value => vec![value],
+    };
+    let mut unknown = vec![];
+    match deserialize(Value::Array(raw.clone()), &mut unknown) {
+        Ok(notifications) => {
+            for path in unknown {
+                if strict {
+                    report.errors.push(format!("Unknown field {}.", path));
+                } else {
+                    report
+                        .warnings
+                        .push(format!("Unknown field {}, which is ignored.", path));
+                }
+            }
+            if strict && !report.is_ok() {
+                return None;
+            }
+            Some((notifications, raw))
+        }
+        Err((path, e)) => {
+            report.errors.push(located(path, e));
+            None
+        }
+    }
+}
+
+/// The fields of the notification which are unknown, and so ignored, such as `token_file`.
+pub fn unknown_fields(notification: &Value) -> Vec<String> {
+    let mut unknown = vec![];
+    // Invalid values are reported when the config is checked
+    let _: Result<NotifyConfig, _> = deserialize(notification, &mut unknown);
+    unknown
+}
+
+/// Deserializes the value, collecting the fields which are ignored and the path of the failing one.
+fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+    de: D,
+    unknown: &mut Vec<String>,
+) -> Result<T, (String, D::Error)> {
+    let mut ignored = |path: serde_ignored::Path| unknown.push(path.to_string());
+    serde_path_to_error::deserialize(serde_ignored::Deserializer::new(de, &mut ignored))
+        .map_err(|e| (e.path().to_string(), e.into_inner()))
+}
+
+/// Prefixes the error with the path of the failing field, unless it's the root.
+fn located(path: String, e: impl Display) -> String {
+    if path == "." {
+        e.to_string()
+    } else {
+        format!("{}: {}", path, e)
+    }
+}
+
+/// Parses the config, resolving the variables and files before deserializing it.
+fn parse(
+    content: &str,
+    format: Format,
+    overrides: &Overrides,
+    report: &mut Report,
+) -> Option<(Config, Value)> {
+    let mut value = match format.value(content) {
+        Ok(value) => value,
+        Err(e) => {
+            report
+                .errors
+                .push(parse_located(content, format).err().unwrap_or(e));
+            return None;
+        }
+    };
+    if let Err(errors) = interpolate::resolve(&mut value, "") {
+        report.errors.extend(errors);
+        return None;
+    }
+    overrides.apply(&mut value);
+
+    let mut unknown = vec![];
+    match deserialize(value.clone(), &mut unknown) {
+        Ok(conf) => {
+            for path in unknown {
+                report
+                    .warnings
+                    .push(format!("Unknown field {}, which is ignored.", path));
+            }
+            Some((conf, value))
+        }
+        Err((path, e)) => {
+            // The content itself tells where it fails, unless it's the resolved value failing
+            let e = parse_located(content, format)
+                .err()
+                .filter(|located| located.contains(&e.to_string()))
+                .unwrap_or_else(|| located(path, e));
+            report.errors.push(e);
+            None
+        }
+    }
+}
+
+/// Parses the content as it is, for the location of the failure.
+fn parse_located(content: &str, format: Format) -> Result<Config, String> {
+    let mut unknown = vec![];
+
+    match format {
+        Format::Json => {
+            let de = &mut serde_json::Deserializer::from_str(content);
+            match deserialize(&mut *de, &mut unknown) {
+                Ok(conf) => de.end().map(|_| conf).map_err(|e| e.to_string()),
+                // Syntax errors have a location but no meaningful path
+                Err((_, e)) if e.is_syntax() || e.is_eof() => Err(e.to_string()),
+                Err((path, e)) => Err(located(path, e)),
+            }
+        }
+        Format::Json5 => {
+            let json5_err = |path: String, e: json5::Error| {
+                let json5::Error::Message { msg, location } = e;
+                match location {
+                    Some(l) => format!(
+                        "{} at line {} column {}",
+                        located(path, msg),
+                        l.line,
+                        l.column
+                    ),
+                    None => located(path, msg),
+                }
+            };
+            match json5::Deserializer::from_str(content) {
+                Ok(mut de) => {
+                    deserialize(&mut de, &mut unknown).map_err(|(path, e)| json5_err(path, e))
+                }
+                Err(e) => Err(json5_err(".".to_string(), e)),
+            }
+        }
+        // Errors of YAML are prefixed with the path already
+        Format::Yaml => deserialize(serde_yaml::Deserializer::from_str(content), &mut unknown)
+            .map_err(|(_, e)| e.to_string()),
+        Format::Toml => deserialize(toml::Deserializer::new(content), &mut unknown)
+            .map_err(|(path, e)| located(path, e)),
+    }
+}
+
+/// Names referenced by the template, such as `name` of `{name}` and `{name:>8}`.
+fn placeholders(template: &str) -> Vec<String> {
+    let mut names = vec![];
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '{' {
+            continue;
+        }
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            continue;
+        }
+        let placeholder: String = chars.by_ref().take_while(|c| *c != '}').collect();
+        let name = placeholder.split(':').next().unwrap_or_default();
+        names.push(name.to_string());
+    }
+
+    names
+}
+
+fn check_template(report: &mut Report, owner: &str, template: &str, names: &HashSet<&str>) {
+    for name in placeholders(template) {
+        if !names.contains(name.as_str()) {
+            report.warnings.push(format!(
+                "{} references [{}], which is not extracted. It won't be filled.",
+                owner, name
+            ));
+        }
+    }
+}
+
+fn check_extractors(report: &mut Report, owner: &str, extractors: &[ContentExtractConfig]) {
+    for extractor in extractors {
+        if let Err(e) = validate_path(&extractor.path) {
+            report.errors.push(format!(
+                "Extractor [{}] of {} has an invalid path: {}",
+                extractor.name, owner, e
+            ));
+        }
+    }
+}
+
+fn validate(conf: &Config, sources: &[Option<String>], report: &mut Report) {
+    let presets = preset::registry(conf.presets.as_deref().unwrap_or_default());
+
+    // The file defining each ID first
+    let mut ids: HashMap<&str, Option<&str>> = HashMap::new();
+    for (n, source) in conf.notifications.iter().zip(sources) {
+        let source = source.as_deref();
+        match ids.get(n.id.as_str()) {
+            None => {
+                ids.insert(n.id.as_str(), source);
+            }
+            Some(None) if source.is_none() => report
+                .errors
+                .push(format!("Notification ID [{}] is duplicated.", n.id)),
+            Some(first) => {
+                let file = |s: Option<&str>| s.unwrap_or("the config file").to_string();
+                report.errors.push(format!(
+                    "Notification ID [{}] is duplicated, in {} and {}.",
+                    n.id,
+                    file(*first),
+                    file(source)
+                ));
+            }
+        }
+    }
+
+    for p in conf.presets.iter().flatten() {
+        let owner = format!("preset [{}]", p.name);
+        let extractors = p.extractors.clone().unwrap_or_default();
+        check_extractors(report, &owner, &extractors);
+        let names = extractors.iter().map(|e| e.name.as_str()).collect();
+        check_template(report, &format!("Message of {}", owner), &p.message, &names);
+    }
+
+    for (n, source) in conf.notifications.iter().zip(sources) {
+        let mut problems = Report::default();
+        validate_notification(conf, n, &presets, &mut problems);
+        report.merge(problems, source.as_deref());
+    }
+
+    for s in conf.schedules.iter().flatten() {
+        if let Err(e) = schedule::parse(&s.cron) {
+            report.errors.push(e);
+        }
+        if !ids.contains_key(s.notification.as_str()) {
+            report.errors.push(format!(
+                "Schedule [{}] sends an unknown notification [{}].",
+                s.cron, s.notification
+            ));
+        }
+    }
+
+    for p in conf.pollers.iter().flatten() {
+        check_extractors(report, &format!("poller of [{}]", p.url), &p.extractors);
+        let names: HashSet<&str> = p.extractors.iter().map(|e| e.name.as_str()).collect();
+        for field in p.fields.iter().flatten() {
+            if !names.contains(field.as_str()) {
+                report.warnings.push(format!(
+                    "Poller of [{}] watches [{}], which is not extracted. Its changes won't be notified.",
+                    p.url, field
+                ));
+            }
+        }
+        if !ids.contains_key(p.notification.as_str()) {
+            report.errors.push(format!(
+                "Poller of [{}] sends an unknown notification [{}].",
+                p.url, p.notification
+            ));
+        }
+    }
+
+    if let Some(admin) = &conf.admin {
+        if admin.token.is_empty() {
+            report
+                .errors
+                .push("Token of the admin API is empty.".to_string());
+        }
+        if admin.ip.is_some() && admin.port.is_none() {
+            report.warnings.push(
+                "Admin API has an IP but no port. It's served on the address of the server."
+                    .to_string(),
+            );
+        }
+    }
+}
+
+fn validate_notification(
+    conf: &Config,
+    n: &NotifyConfig,
+    presets: &HashMap<String, Preset>,
+    report: &mut Report,
+) {
+    let owner = format!("notification [{}]", n.id);
+
+    if n.groups.is_none() && n.users.is_none() && n.routes.is_none() {
+        report.warnings.push(format!(
+            "Notification with ID [{}] has no group or person specified. It won't take any effect.",
+            n.id
+        ));
+    }
+    if let Some(AuthConfig::Mtls) = &n.auth {
+        if conf
+            .server
+            .tls
+            .as_ref()
+            .is_none_or(|t| t.client_ca.is_none())
+        {
+            report.warnings.push(format!(
+                "Notification with ID [{}] authenticates with mTLS, but no client CA is configured. All requests will be rejected.",
+                n.id
+            ));
+        }
+    }
+
+    check_extractors(report, &owner, n.extractors.as_deref().unwrap_or_default());
+
+    // The extractors filling the templates, and the templates filled
+    let preset = n.preset.as_ref().and_then(|name| presets.get(name));
+    let extractors = match preset {
+        Some(p) => p.merge_extractors(n),
+        None => n.extractors.clone().unwrap_or_default(),
+    };
+    let mut names: HashSet<&str> = extractors.iter().map(|e| e.name.as_str()).collect();
+
+    match (&n.preset, preset) {
+        (Some(name), None) => report.warnings.push(format!(
+            "Notification with ID [{}] uses an unknown preset [{}]. It won't take any effect.",
+            n.id, name
+        )),
+        (_, Some(p)) => {
+            if p.renderer == Renderer::Alerts {
+                names.extend(["status", "count", "group_labels"]);
+            }
+            let message = n.message.as_deref().unwrap_or(&p.message);
+            check_template(report, &format!("Message of {}", owner), message, &names);
+            if let Some(resolved) = n.resolved_message.as_deref() {
+                check_template(report, &format!("Resolved message of {}", owner), resolved, &names);
+            }
+        }
+        (None, None) => match &n.message {
+            Some(message) if n.extra.unwrap_or(false) => {
+                check_template(report, &format!("Message of {}", owner), message, &names)
+            }
+            Some(_) => {}
+            None => report.warnings.push(format!(
+                "Notification with ID [{}] has no message specified. An empty message will be sent.",
+                n.id
+            )),
+        },
+    }
+
+    if let Some(key) = n.dedup.as_ref().and_then(|d| d.key.as_deref()) {
+        check_template(report, &format!("Dedup key of {}", owner), key, &names);
+    }
+    if let Some(message) = n.digest.as_ref().and_then(|d| d.message.as_deref()) {
+        let names = HashSet::from(["id", "count", "events"]);
+        check_template(report, &format!("Digest of {}", owner), message, &names);
+    }
+
+    let now = Utc::now();
+    let hmac = matches!(
+        n.auth.clone().or(preset.map(|p| p.auth.clone())),
+        Some(AuthConfig::Hmac(_))
+    );
+    for token in n.all_tokens() {
+        let label = token.label.unwrap_or_default();
+        if hmac && auth::is_hashed(&token.token) {
+            report.warnings.push(format!(
+                "Token [{}] of notification with ID [{}] is hashed, but HMAC signatures can only be verified with plaintext tokens.",
+                label, n.id
+            ));
+        }
+        if token.expires_at.is_some_and(|t| t <= now) {
+            report.warnings.push(format!(
+                "Token [{}] of notification with ID [{}] has expired.",
+                label, n.id
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{check, placeholders};
+    use crate::config::{Format, LogLevel, Overrides};
+
+    #[test]
+    fn test_check() {
+        let (conf, report) = check(
+            "{\n  \"server\": {\n    \"ip\": \"0.0.0.0\",\n    \"port\": \"x\"",
+            Format::Json,
+            Path::new("."),
+            &Overrides::default(),
+        );
+        assert!(conf.is_none());
+        assert_eq!(
+            report.errors,
+            vec!["server.port: invalid type: string \"x\", expected u16 at line 4 column 15"]
+        );
+
+        let (conf, report) = check(
+            r#"{
+                "server": { "ip": "0.0.0.0", "port": 10000 },
+                "onebot": { "url": "http://localhost:3000" },
+                "notifications": [
+                    {
+                        "id": "a", "groups": ["1"], "mention": ["2"],
+                        "message": "{user} {repo}", "extra": true,
+                        "extractors": [
+                            { "name": "user", "path": "sender.name" },
+                            { "name": "ids", "path": "commits[0].id" }
+                        ]
+                    },
+                    { "id": "a", "users": ["1"], "message": "{x}" }
+                ],
+                "schedules": [{ "cron": "0 9 * * *", "notification": "b" }],
+                "pollers": [{
+                    "url": "http://localhost/status.json", "interval": 60, "notification": "a",
+                    "extractors": [{ "name": "status", "path": "status" }], "fields": ["state"]
+                }]
+            }"#,
+            Format::Json,
+            Path::new("."),
+            &Overrides::default(),
+        );
+        assert!(conf.is_some());
+        assert_eq!(
+            report.errors,
+            vec![
+                "Notification ID [a] is duplicated.",
+                "Extractor [ids] of notification [a] has an invalid path: indices must be a segment of their own, e.g. items.[0], in path commits[0].id",
+                "Schedule [0 9 * * *] sends an unknown notification [b].",
+            ]
+        );
+        assert_eq!(
+            report.warnings,
+            vec![
+                "Unknown field notifications.0.mention, which is ignored.",
+                "Message of notification [a] references [repo], which is not extracted. It won't be filled.",
+                "Poller of [http://localhost/status.json] watches [state], which is not extracted. Its changes won't be notified.",
+            ]
+        );
+
+        assert_eq!(placeholders("{a}{{b}} {c:>4}"), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_formats() {
+        let json5 = r#"{
+            // Comments and trailing commas are allowed
+            server: { ip: "0.0.0.0", port: 10000, },
+            onebot: { url: "http://localhost:3000" },
+            notifications: [{ id: "a", users: ["1"], message: "a", mention: [] }],
+        }"#;
+        let yaml = r#"
+server: { ip: 0.0.0.0, port: 10000 }
+onebot: { url: "http://localhost:3000" }
+notifications:
+  - id: a
+    users: ["1"]
+    message: a
+    mention: []
+"#;
+        let toml = r#"
+server = { ip = "0.0.0.0", port = 10000 }
+onebot = { url = "http://localhost:3000" }
+
+[[notifications]]
+id = "a"
+users = ["1"]
+message = "a"
+mention = []
+"#;
+
+        for (content, format) in [
+            (json5, Format::Json5),
+            (yaml, Format::Yaml),
+            (toml, Format::Toml),
+        ] {
+            let (conf, report) = check(content, format, Path::new("."), &Overrides::default());
+            assert_eq!(conf.unwrap().0.notifications[0].id, "a", "{:?}", format);
+            assert!(report.errors.is_empty(), "{:?}", format);
+            assert_eq!(
+                report.warnings,
+                vec!["Unknown field notifications.0.mention, which is ignored."],
+                "{:?}",
+                format
+            );
+            assert!(format.value(content).unwrap()["notifications"].is_array());
+        }
+
+        let (_, report) = check(
+            "{ server: { ip: 1 } }",
+            Format::Json5,
+            Path::new("."),
+            &Overrides::default(),
+        );
+        assert_eq!(
+            report.errors,
+            vec!["server.ip: invalid type: integer `1`, expected a string at line 1 column 17"]
+        );
+        let (_, report) = check(
+            "server:\n  ip: 0.0.0.0\n  port: x\n",
+            Format::Yaml,
+            Path::new("."),
+            &Overrides::default(),
+        );
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].starts_with("server.port: invalid type"));
+        assert!(report.errors[0].contains("line 3"));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("notify-bot-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("notifications.d")).unwrap();
+        fs::write(
+            dir.join("notifications.d/a.yaml"),
+            "- id: b\n  groups: ['1']\n  message: b\n- id: c\n  groups: ['1']\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("notifications.d/b.json"),
+            r#"{ "id": "a", "users": ["1"], "message": "a" }"#,
+        )
+        .unwrap();
+
+        let content = r#"{
+            "server": { "ip": "0.0.0.0", "port": 10000 },
+            "onebot": { "url": "http://localhost:3000" },
+            "notifications": [{ "id": "a", "groups": ["1"], "message": "a" }],
+            "include": ["notifications.d/*"]
+        }"#;
+        let (conf, report) = check(content, Format::Json, &dir, &Overrides::default());
+        let (conf, value) = conf.unwrap();
+        let ids: Vec<&str> = conf.notifications.iter().map(|n| n.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "c", "a"]);
+        assert_eq!(value["notifications"].as_array().unwrap().len(), 4);
+
+        let file = |name: &str| dir.join("notifications.d").join(name).display().to_string();
+        assert_eq!(
+            report.errors,
+            vec![format!(
+                "Notification ID [a] is duplicated, in the config file and {}.",
+                file("b.json")
+            )]
+        );
+        assert_eq!(
+            report.warnings,
+            vec![format!(
+                "{}: Notification with ID [c] has no message specified. An empty message will be sent.",
+                file("a.yaml")
+            )]
+        );
+
+        fs::write(dir.join("notifications.d/b.json"), r#"{ "id": 1 }"#).unwrap();
+        let (_, report) = check(content, Format::Json, &dir, &Overrides::default());
+        assert_eq!(
+            report.errors,
+            vec![format!(
+                "{}: [0].id: invalid type: integer `1`, expected a string",
+                file("b.json")
+            )]
+        );
+
+        // The store of the admin API is merged as well, without resolving any variable, and its
+        // overrides apply to the notifications of the config files
+        fs::remove_file(dir.join("notifications.d/b.json")).unwrap();
+        fs::write(
+            dir.join("admin.json"),
+            r#"{
+                "notifications": [{ "id": "d", "groups": ["1"], "message": "${NOTIFY_BOT_TEST_UNSET}" }],
+                "overrides": { "a": { "enabled": false }, "e": { "enabled": false } }
+            }"#,
+        )
+        .unwrap();
+        let content = content.replace(
+            r#""include""#,
+            r#""admin": { "token": "secret" }, "include""#,
+        );
+        let (conf, report) = check(&content, Format::Json, &dir, &Overrides::default());
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        let (conf, _) = conf.unwrap();
+        let d = conf.notifications.iter().find(|n| n.id == "d").unwrap();
+        assert_eq!(d.message.as_deref(), Some("${NOTIFY_BOT_TEST_UNSET}"));
+        let a = conf.notifications.iter().find(|n| n.id == "a").unwrap();
+        assert!(!a.is_enabled());
+        assert!(
+            report
+                .warnings
+                .iter()
+                .any(|w| w
+                    .ends_with("Override of notification [e], which doesn't exist, is ignored."))
+        );
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let overrides = Overrides {
+            port: Some(10001),
+            onebot_url: Some("http://onebot:3000".to_string()),
+            log_level: Some(LogLevel::Debug),
+            ..Default::default()
+        };
+        let (conf, report) = check(
+            "server:\n  ip: 0.0.0.0\n  port: 10000\nlog:\nnotifications: []\n",
+            Format::Yaml,
+            Path::new("."),
+            &overrides,
+        );
+        assert!(report.is_ok(), "{:?}", report.errors);
+        let (conf, _) = conf.unwrap();
+        assert_eq!(conf.server.ip, "0.0.0.0");
+        assert_eq!(conf.server.port, 10001);
+        assert_eq!(conf.onebot.url, "http://onebot:3000");
+        assert_eq!(conf.log.unwrap().level, Some(LogLevel::Debug));
+    }
+}
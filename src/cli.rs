@@ -0,0 +1,298 @@
+use std::{
+    fs,
+    io::{self, stdin, stdout, Read, Write},
+    path::Path,
+    process,
+    time::Duration,
+};
+
+use reqwest::Client;
+
+use crate::{
+    config::{NotifyConfig, Overrides, RuntimeConfig},
+    onebot::{self, Message},
+    reload, service,
+};
+
+fn fail(msg: String) -> ! {
+    println!("\x1b[31m{}\x1b[0m", msg);
+    process::exit(1)
+}
+
+/// Reads the config for a command, exiting if it's invalid.
+fn load(path: &str, overrides: &Overrides) -> RuntimeConfig {
+    match reload::read(Path::new(path), overrides) {
+        Ok((conf, _, _)) => reload::build(&conf),
+        Err(report) => {
+            report.print();
+            process::exit(1)
+        }
+    }
+}
+
+fn notification<'a>(conf: &'a RuntimeConfig, id: &str) -> &'a NotifyConfig {
+    conf.notifications
+        .get(id)
+        .unwrap_or_else(|| fail(format!("No notification found with ID [{}].", id)))
+}
+
+/// Reads the request body from the file, or stdin for `-`, defaulting to `{}`.
+fn read_body(file: Option<&str>) -> String {
+    match file {
+        None => "{}".to_string(),
+        Some("-") => {
+            let mut body = String::new();
+            if let Err(e) = stdin().read_to_string(&mut body) {
+                fail(format!("Failed to read the body from stdin: {}", e));
+            }
+            body
+        }
+        Some(file) => fs::read_to_string(file)
+            .unwrap_or_else(|e| fail(format!("Failed to read the body from {}: {}", file, e))),
+    }
+}
+
+/// The targets of the message, such as `group:1, user:2 (mentioning 3)`.
+fn describe(msg: &Message) -> String {
+    let targets: Vec<String> = msg.targets().iter().map(|t| t.to_string()).collect();
+    let targets = if targets.is_empty() {
+        "nobody".to_string()
+    } else {
+        targets.join(", ")
+    };
+    if msg.mentions.is_empty() {
+        targets
+    } else {
+        format!("{} (mentioning {})", targets, msg.mentions.join(", "))
+    }
+}
+
+/// Checks the config, exiting with 1 if it has any error.
+pub fn check(path: &str, overrides: &Overrides) {
+    match reload::read(Path::new(path), overrides) {
+        Ok((_, _, report)) => {
+            report.print();
+            println!("\x1b[32mConfig file '{}' is valid.\x1b[0m", path);
+        }
+        Err(report) => {
+            report.print();
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints the messages the notification renders from the body, without sending them.
+pub fn render(path: &str, overrides: &Overrides, id: &str, body: Option<&str>) {
+    let conf = load(path, overrides);
+    let cfg = notification(&conf, id);
+    if let Err(e) = render_to(&mut stdout(), &conf, cfg, &read_body(body)) {
+        fail(format!("Failed to print the messages: {}", e));
+    }
+}
+
+fn render_to(
+    out: &mut impl Write,
+    conf: &RuntimeConfig,
+    cfg: &NotifyConfig,
+    body: &str,
+) -> io::Result<()> {
+    let messages = service::render_messages(body, cfg, conf);
+    if messages.is_empty() {
+        writeln!(out, "Nothing would be sent.")?;
+    }
+    for msg in messages {
+        writeln!(out, "\x1b[34mTo {}\x1b[0m", describe(&msg))?;
+        writeln!(out, "{}\n", msg.text)?;
+    }
+    Ok(())
+}
+
+/// Renders the messages of the notification and sends them right away, unless it's disabled.
+///
+/// Rate limits, deduplication, quiet hours and digests are skipped, as they only make sense for
+/// a running server. Dry runs print the payloads instead.
+pub async fn send(path: &str, overrides: &Overrides, id: &str, body: Option<&str>) {
+    let conf = load(path, overrides);
+    let cfg = notification(&conf, id);
+    if !cfg.is_enabled() {
+        fail(format!("Notification [{}] is disabled.", id));
+    }
+    let client = Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+
+    match send_to(&mut stdout(), &conf, cfg, &read_body(body), &client).await {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => fail(format!("Failed to print the results: {}", e)),
+    }
+}
+
+/// Sends the messages, returning whether all of them are sent.
+async fn send_to(
+    out: &mut impl Write,
+    conf: &RuntimeConfig,
+    cfg: &NotifyConfig,
+    body: &str,
+    client: &Client,
+) -> io::Result<bool> {
+    let messages = service::render_messages(body, cfg, conf);
+    if conf.dry_run(cfg) {
+        writeln!(out, "Dry run. Nothing is sent. Payloads:")?;
+        for payload in service::payloads_of(&messages) {
+            writeln!(out, "{}", payload)?;
+        }
+        return Ok(true);
+    }
+    if messages.is_empty() {
+        writeln!(out, "Nothing to be sent.")?;
+    }
+    let mut sent = true;
+    for msg in messages {
+        for target in msg.targets() {
+            match onebot::deliver(client, &conf.onebot, &msg.to(&target)).await {
+                Ok(_) => writeln!(out, "\x1b[32mSent to {}.\x1b[0m", target)?,
+                Err(e) => {
+                    writeln!(out, "\x1b[31mFailed to send to {}: {}\x1b[0m", target, e)?;
+                    sent = false;
+                }
+            }
+        }
+    }
+    Ok(sent)
+}
+
+/// Prints the notifications with their targets and endpoints.
+pub fn list(path: &str, overrides: &Overrides) {
+    let conf = load(path, overrides);
+    if let Err(e) = list_to(&mut stdout(), &conf) {
+        fail(format!("Failed to print the notifications: {}", e));
+    }
+}
+
+fn list_to(out: &mut impl Write, conf: &RuntimeConfig) -> io::Result<()> {
+    let scheme = if conf.server.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+
+    let mut notifications: Vec<&NotifyConfig> = conf.notifications.values().collect();
+    notifications.sort_by(|a, b| a.id.cmp(&b.id));
+    for n in notifications {
+        writeln!(
+            out,
+            "\x1b[34m[{}]\x1b[0m POST {}://{}:{}/notify-{}",
+            n.id, scheme, conf.server.ip, conf.server.port, n.id
+        )?;
+        writeln!(out, "    To {}", describe(&Message::new(String::new(), n)))?;
+        for route in n.routes.iter().flatten() {
+            let msg = Message {
+                text: String::new(),
+                groups: route.groups.clone().unwrap_or_default(),
+                users: route.users.clone().unwrap_or_default(),
+                mentions: route.mentions.clone().unwrap_or_default(),
+            };
+            writeln!(
+                out,
+                "    To {} for alerts labelled {}={}",
+                describe(&msg),
+                route.label,
+                route.value
+            )?;
+        }
+        if let Some(preset) = &n.preset {
+            writeln!(out, "    Preset: {}", preset)?;
+        }
+        if conf.dry_run(n) {
+            writeln!(out, "    Dry run")?;
+        }
+        if !n.is_enabled() {
+            writeln!(out, "    Disabled")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use reqwest::Client;
+
+    use super::{describe, list_to, load, render_to, send_to};
+    use crate::{config::Overrides, onebot::Message};
+
+    #[test]
+    fn test_describe() {
+        let mut msg = Message {
+            text: String::new(),
+            groups: vec!["1".to_string()],
+            users: vec!["3".to_string()],
+            mentions: vec![],
+        };
+        assert_eq!(describe(&msg), "group:1, user:3");
+        msg.mentions = vec!["2".to_string()];
+        assert_eq!(describe(&msg), "group:1, user:3 (mentioning 2)");
+        msg.groups.clear();
+        msg.users.clear();
+        assert_eq!(describe(&msg), "nobody (mentioning 2)");
+    }
+
+    #[actix_web::test]
+    async fn test_commands() {
+        let path = std::env::temp_dir().join(format!("notify-bot-cli-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{
+                "server": { "ip": "127.0.0.1", "port": 10000 },
+                "onebot": { "url": "http://127.0.0.1:3000" },
+                "notifications": [
+                    { "id": "push", "groups": ["1"], "mentions": ["2"], "extra": true,
+                      "message": "{user} pushed", "dry_run": true,
+                      "extractors": [{ "name": "user", "path": "pusher.name" }] },
+                    { "id": "alerts", "users": ["3"], "preset": "alertmanager", "enabled": false }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let conf = load(&path.to_string_lossy(), &Overrides::default());
+        let body = r#"{ "pusher": { "name": "octocat" } }"#;
+        let output = |out: Vec<u8>| String::from_utf8(out).unwrap();
+
+        let mut out = vec![];
+        list_to(&mut out, &conf).unwrap();
+        assert_eq!(
+            output(out),
+            "\x1b[34m[alerts]\x1b[0m POST http://127.0.0.1:10000/notify-alerts\n    To user:3\n    Preset: alertmanager\n    Disabled\n\
+             \x1b[34m[push]\x1b[0m POST http://127.0.0.1:10000/notify-push\n    To group:1 (mentioning 2)\n    Dry run\n"
+        );
+
+        let mut out = vec![];
+        render_to(&mut out, &conf, &conf.notifications["push"], body).unwrap();
+        assert_eq!(
+            output(out),
+            "\x1b[34mTo group:1 (mentioning 2)\x1b[0m\noctocat pushed\n\n"
+        );
+
+        let mut out = vec![];
+        let sent = send_to(
+            &mut out,
+            &conf,
+            &conf.notifications["push"],
+            body,
+            &Client::new(),
+        )
+        .await
+        .unwrap();
+        assert!(sent);
+        assert_eq!(
+            output(out),
+            "Dry run. Nothing is sent. Payloads:\n\
+             {\"action\":\"send_group_msg\",\"payload\":{\"group_id\":\"1\",\"message\":[{\"data\":{\"text\":\"octocat pushed\\n [CQ:at,qq=2]\"},\"type\":\"text\"}]}}\n"
+        );
+
+        let _ = fs::remove_file(path);
+    }
+}
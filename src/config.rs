@@ -1,12 +1,46 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
+use chrono::{DateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use clap::{Args, ValueEnum};
 use serde::Deserialize;
-use serde_json::Error;
+use serde_json::{json, Value};
+
+use crate::{access::Cidr, preset::Preset};
 
 #[derive(Clone)]
 pub struct RuntimeConfig {
+    pub server: ServerConfig,
     pub onebot: OneBotConfig,
     pub notifications: HashMap<String, NotifyConfig>,
+    pub presets: HashMap<String, Preset>,
+    pub schedules: Vec<ScheduleConfig>,
+    pub pollers: Vec<PollerConfig>,
+    pub dry_run: bool,
+    pub admin: Option<AdminConfig>,
+}
+
+impl RuntimeConfig {
+    /// The auth method of the notification, falling back to the one of its preset, or Bearer.
+    pub fn auth_method(&self, notify: &NotifyConfig) -> AuthConfig {
+        notify
+            .auth
+            .clone()
+            .or(notify
+                .preset
+                .as_ref()
+                .and_then(|p| self.presets.get(p))
+                .map(|p| p.auth.clone()))
+            .unwrap_or(AuthConfig::Bearer)
+    }
+
+    /// Whether the messages of the notification are only logged, falling back to the global flag.
+    pub fn dry_run(&self, notify: &NotifyConfig) -> bool {
+        notify.dry_run.unwrap_or(self.dry_run)
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -14,12 +48,52 @@ pub struct Config {
     pub server: ServerConfig,
     pub onebot: OneBotConfig,
     pub log: Option<LogConfig>,
+    pub presets: Option<Vec<PresetConfig>>,
     pub notifications: Vec<NotifyConfig>,
+    pub schedules: Option<Vec<ScheduleConfig>>,
+    pub pollers: Option<Vec<PollerConfig>>,
+
+    // Glob patterns of the files defining more notifications, relative to the config file
+    pub include: Option<Vec<String>>,
+
+    // Logs the messages and returns them in the response instead of sending them
+    pub dry_run: Option<bool>,
+
+    // Reloads the config when the file is modified, besides on SIGHUP
+    pub watch: Option<bool>,
+
+    // API managing the notifications at runtime
+    pub admin: Option<AdminConfig>,
+}
+
+/// Format of the config file, chosen by its extension.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Json,
+    /// JSON5, a superset of JSON with comments and trailing commas, read for `.jsonc` as well.
+    Json5,
+    Yaml,
+    Toml,
 }
 
-impl Config {
-    pub fn parse(conf: String) -> Result<Config, Error> {
-        serde_json::from_str(conf.as_str())
+impl Format {
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json5" | "jsonc") => Format::Json5,
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            _ => Format::Json,
+        }
+    }
+
+    /// Parses the content as a raw value, regardless of the fields.
+    pub fn value(self, content: &str) -> Result<Value, String> {
+        match self {
+            Format::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            Format::Json5 => json5::from_str(content).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+        }
     }
 }
 
@@ -27,6 +101,27 @@ impl Config {
 pub struct ServerConfig {
     pub ip: String,
     pub port: u16,
+    pub tls: Option<TlsConfig>,
+
+    // Networks allowed to and denied from sending any request
+    pub allow: Option<Vec<Cidr>>,
+    pub deny: Option<Vec<Cidr>>,
+
+    // Proxies whose Forwarded and X-Forwarded-For headers are trusted
+    pub trusted_proxies: Option<Vec<Cidr>>,
+
+    // Limits shared by all the notifications
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+    // PEM files of the certificate chain and the private key
+    pub cert: String,
+    pub key: String,
+
+    // PEM file of the CAs verifying client certificates, enables mTLS
+    pub client_ca: Option<String>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -34,12 +129,43 @@ pub struct OneBotConfig {
     pub url: String,
 }
 
+#[derive(Deserialize, Clone)]
+pub struct AdminConfig {
+    // Bearer token of the admin API, which may be hashed
+    pub token: String,
+
+    // Serves the admin API on a separate address without TLS, instead of the server's
+    pub ip: Option<String>,
+    pub port: Option<u16>,
+
+    // JSON file keeping the notifications managed by the API and the overrides of the other ones,
+    // relative to the config file, defaults to admin.json
+    pub store: Option<String>,
+}
+
+impl AdminConfig {
+    /// The store file, relative to the directory of the config file.
+    pub fn store(&self, dir: &Path) -> PathBuf {
+        dir.join(self.store.as_deref().unwrap_or("admin.json"))
+    }
+}
+
+// Kept in the admin store for the notifications of the config files, which the API can't edit
+#[derive(Deserialize, Clone)]
+pub struct NotifyOverride {
+    // Replaces `enabled` of the notification
+    pub enabled: Option<bool>,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct LogConfig {
     pub path: Option<String>,
     pub size: Option<u64>,
     pub backup: Option<u32>,
     pub compress: Option<bool>,
+
+    // The least severe level logged, defaults to info
+    pub level: Option<LogLevel>,
 }
 
 impl LogConfig {
@@ -49,6 +175,77 @@ impl LogConfig {
             size: None,
             backup: None,
             compress: None,
+            level: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Settings given on the command line or by environment variables, taking precedence over the
+/// config file.
+#[derive(Args, Clone, Default)]
+pub struct Overrides {
+    /// The ip to be listened on, overriding server.ip.
+    #[arg(long, env = "NOTIFY_BOT_IP", global = true)]
+    pub ip: Option<String>,
+
+    /// The port to be listened on, overriding server.port.
+    #[arg(long, env = "NOTIFY_BOT_PORT", global = true)]
+    pub port: Option<u16>,
+
+    /// The url of the OneBot server, overriding onebot.url.
+    #[arg(long, env = "NOTIFY_BOT_ONEBOT_URL", global = true)]
+    pub onebot_url: Option<String>,
+
+    /// The least severe level logged, overriding log.level.
+    #[arg(long, value_enum, env = "NOTIFY_BOT_LOG_LEVEL", global = true)]
+    pub log_level: Option<LogLevel>,
+
+    /// The log file path, overriding log.path.
+    #[arg(long, env = "NOTIFY_BOT_LOG_PATH", global = true)]
+    pub log_path: Option<String>,
+}
+
+impl Overrides {
+    /// Sets the fields of the raw config, so that sections may be left out of the file if given here.
+    pub fn apply(&self, value: &mut Value) {
+        let Some(obj) = value.as_object_mut() else {
+            return;
+        };
+        let mut set = |section: &str, field: &str, v: Value| {
+            let section = obj.entry(section).or_insert(Value::Null);
+            if section.is_null() {
+                *section = json!({});
+            }
+            if let Some(section) = section.as_object_mut() {
+                section.insert(field.to_string(), v);
+            }
+        };
+
+        if let Some(ip) = &self.ip {
+            set("server", "ip", json!(ip));
+        }
+        if let Some(port) = self.port {
+            set("server", "port", json!(port));
+        }
+        if let Some(url) = &self.onebot_url {
+            set("onebot", "url", json!(url));
+        }
+        if let Some(level) = self.log_level {
+            let level = level.to_possible_value().unwrap_or_default();
+            set("log", "level", json!(level.get_name()));
+        }
+        if let Some(path) = &self.log_path {
+            set("log", "path", json!(path));
         }
     }
 }
@@ -58,20 +255,210 @@ pub struct NotifyConfig {
     // ID of the notification service
     pub id: String,
 
+    // Rejects the requests and skips the schedules and pollers if false, defaults to true
+    pub enabled: Option<bool>,
+
     // Token for the notification service
     pub token: Option<String>,
 
+    // Additional tokens, allowing them to be rotated
+    pub tokens: Option<Vec<TokenConfig>>,
+
+    // How the token is presented, defaults to the one of the preset or Bearer
+    pub auth: Option<AuthConfig>,
+
+    // Networks allowed to and denied from triggering the notification
+    pub allow: Option<Vec<Cidr>>,
+    pub deny: Option<Vec<Cidr>>,
+
+    // Limits of the notification
+    pub rate_limit: Option<RateLimitConfig>,
+
+    // Drops repeated deliveries of the same event
+    pub dedup: Option<DedupConfig>,
+
+    // Buffers the messages and sends them as a single summary
+    pub digest: Option<DigestConfig>,
+
+    // Holds, drops or redirects the messages during quiet hours
+    pub quiet: Option<QuietConfig>,
+
+    // Logs the messages and returns them in the response instead of sending them, defaults to the
+    // global one
+    pub dry_run: Option<bool>,
+
     // Notification targets
     pub groups: Option<Vec<String>>,
     pub users: Option<Vec<String>>,
 
     // Notification content
-    pub message: String,
+    pub message: Option<String>,
     pub mentions: Option<Vec<String>>,
 
     // Custom content extraction
     pub extra: Option<bool>,
     pub extractors: Option<Vec<ContentExtractConfig>>,
+
+    // Name of the preset interpreting the request body, e.g. "alertmanager"
+    pub preset: Option<String>,
+
+    // Message for resolved alerts, used by presets telling them apart from firing ones
+    pub resolved_message: Option<String>,
+
+    // Label based routing to alternative targets, used by presets with labelled payloads
+    pub routes: Option<Vec<RouteConfig>>,
+}
+
+impl NotifyConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    /// All the tokens accepted by the notification, including the expired ones.
+    pub fn all_tokens(&self) -> Vec<TokenConfig> {
+        let mut tokens = vec![];
+        if let Some(token) = &self.token {
+            tokens.push(TokenConfig {
+                token: token.clone(),
+                label: Some("token".to_string()),
+                not_before: None,
+                expires_at: None,
+            });
+        }
+        for (idx, token) in self.tokens.iter().flatten().enumerate() {
+            tokens.push(TokenConfig {
+                label: Some(token.label.clone().unwrap_or(format!("tokens[{}]", idx))),
+                ..token.clone()
+            });
+        }
+        tokens
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TokenConfig {
+    pub token: String,
+
+    // Name of the token, logged when it authenticates a request
+    pub label: Option<String>,
+
+    // Validity period of the token, in RFC 3339 format
+    pub not_before: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl TokenConfig {
+    pub fn is_valid_at(&self, time: DateTime<Utc>) -> bool {
+        self.not_before.is_none_or(|t| t <= time) && self.expires_at.is_none_or(|t| time < t)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RateLimitConfig {
+    // Limit on all the events
+    pub total: Option<BucketConfig>,
+    // Limit per client address
+    pub ip: Option<BucketConfig>,
+    // Limit per target group or user
+    pub target: Option<BucketConfig>,
+
+    // What happens to the events exceeding the limits, defaults to reject
+    pub action: Option<RateLimitAction>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BucketConfig {
+    // Number of events allowed per period
+    pub rate: u32,
+    // Length of the period in seconds, defaults to 60
+    pub per: Option<u64>,
+    // Number of events allowed at once, defaults to the rate
+    pub burst: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    // Responds with 429 Too Many Requests
+    Reject,
+    // Responds as usual but sends nothing
+    Drop,
+    // Sends the held back messages at once when the limit allows
+    Coalesce,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DedupConfig {
+    // Seconds during which the same event is delivered only once
+    pub window: u64,
+
+    // Template of the extracted contents identifying the event, e.g. "{group_key}:{status}"
+    pub key: Option<String>,
+    // Header identifying the delivery, e.g. "X-GitHub-Delivery", preferred over the key
+    pub header: Option<String>,
+
+    // Number of events remembered, defaults to 1000
+    pub capacity: Option<usize>,
+    // File remembering the events across restarts
+    pub persist: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DigestConfig {
+    // Seconds the messages are buffered for, counted from the first one
+    pub window: u64,
+    // Sends the digest early once this many events are buffered
+    pub count: Option<usize>,
+
+    // Template of the digest, with the variables {id}, {count} and {events}
+    pub message: Option<String>,
+    // Separator between the buffered messages in {events}, defaults to "\n\n"
+    pub sep: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct QuietConfig {
+    // Time zone of the ranges, e.g. "Asia/Shanghai", defaults to UTC
+    pub timezone: Option<Tz>,
+    pub ranges: Vec<QuietRange>,
+
+    // What happens to the messages during quiet hours, defaults to hold
+    pub action: Option<QuietAction>,
+
+    // Targets the messages are redirected to
+    pub groups: Option<Vec<String>>,
+    pub users: Option<Vec<String>>,
+    pub mentions: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct QuietRange {
+    // Weekdays the range starts on, e.g. ["sat", "sun"], defaults to every day
+    pub days: Option<Vec<Weekday>>,
+    // Start and end of the range, e.g. "22:00" and "08:00", ending the day after if not later
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuietAction {
+    // Sends the messages when the quiet hours end
+    Hold,
+    // Sends nothing
+    Drop,
+    // Sends the messages to the targets of the quiet hours instead
+    Redirect,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RouteConfig {
+    pub label: String,
+    pub value: String,
+
+    pub groups: Option<Vec<String>>,
+    pub users: Option<Vec<String>>,
+    pub mentions: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -80,4 +467,115 @@ pub struct ContentExtractConfig {
     pub path: String,
     pub fallback: Option<String>,
     pub sep: Option<String>,
+
+    // Keeps the first elements extracted from arrays, followed by "and N more"
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthConfig {
+    // `Authorization: Bearer <token>`
+    Bearer,
+
+    // `Authorization: Basic <username:token>`
+    Basic {
+        // Accepts any username if not specified
+        username: Option<String>,
+    },
+
+    // Token in an arbitrary header, e.g. "X-Gitlab-Token"
+    Header {
+        name: String,
+        // Prefix to be stripped from the header value
+        prefix: Option<String>,
+    },
+
+    // Token in the query string, e.g. `/notify-{id}?token=<token>`
+    Query {
+        // Defaults to "token"
+        param: Option<String>,
+    },
+
+    // Identity of the verified client certificate, compared with the tokens
+    Mtls,
+
+    // Signature of the body keyed by the token
+    Hmac(HmacConfig),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HmacConfig {
+    // Header carrying the signature, e.g. "X-Hub-Signature-256"
+    pub header: String,
+    // Defaults to sha256
+    pub algorithm: Option<HmacAlgorithm>,
+    // Prefix before the signature, e.g. "sha256="
+    pub prefix: Option<String>,
+    // Defaults to hex
+    pub encoding: Option<SignatureEncoding>,
+
+    // Header carrying the unix timestamp of the request, for replay protection
+    pub timestamp_header: Option<String>,
+    // Accepted difference from the local clock in seconds, defaults to 300
+    pub tolerance: Option<u64>,
+    // Signed content built from {body} and {timestamp}, defaults to "{body}"
+    pub payload: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum HmacAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    Hex,
+    Base64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PresetConfig {
+    // Name referenced by the `preset` field of notifications
+    pub name: String,
+
+    pub auth: Option<AuthConfig>,
+    pub message: String,
+    pub extractors: Option<Vec<ContentExtractConfig>>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ScheduleConfig {
+    // Cron expression, e.g. "0 9 * * Mon-Fri", optionally with seconds first and years last
+    pub cron: String,
+    // Time zone of the expression, defaults to UTC
+    pub timezone: Option<Tz>,
+
+    // ID of the notification sent
+    pub notification: String,
+    // Request body the message is rendered from, defaults to {}
+    pub body: Option<Value>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PollerConfig {
+    // URL fetched with GET, responding with JSON
+    pub url: String,
+    pub headers: Option<HashMap<String, String>>,
+    // Seconds between the fetches
+    pub interval: u64,
+    // Seconds to wait for the response, defaults to 10
+    pub timeout: Option<u64>,
+
+    // Contents compared with the previous fetch
+    pub extractors: Vec<ContentExtractConfig>,
+    // Names of the extracted contents whose change is notified, defaults to all of them
+    pub fields: Option<Vec<String>>,
+
+    // ID of the notification sent, with the body {"url", "changed", "old", "new"}
+    pub notification: String,
 }
@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lru::LruCache;
+use tklog::warn;
+
+use crate::config::DedupConfig;
+
+const DEFAULT_CAPACITY: usize = 1000;
+
+/// Events recently seen by a notification, and the number of lines appended to its file.
+struct Seen {
+    events: LruCache<String, u64>,
+    lines: usize,
+}
+
+/// Events recently seen by every notification, keyed by the notification ID.
+#[derive(Default)]
+pub struct Deduplicator {
+    seen: Mutex<HashMap<String, Seen>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Loads the events persisted by [`Deduplicator::remember`], one per line from the oldest to the
+/// newest.
+fn load(cfg: &DedupConfig) -> Seen {
+    let capacity =
+        NonZeroUsize::new(cfg.capacity.unwrap_or(DEFAULT_CAPACITY)).unwrap_or(NonZeroUsize::MIN);
+    let mut seen = Seen {
+        events: LruCache::new(capacity),
+        lines: 0,
+    };
+
+    if let Some(path) = &cfg.persist {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                for line in content.lines().filter(|l| !l.trim().is_empty()) {
+                    match serde_json::from_str::<(String, u64)>(line) {
+                        Ok((key, time)) => {
+                            seen.events.put(key, time);
+                        }
+                        Err(e) => warn!(format!("Failed to parse dedup file {}: {}", path, e)),
+                    }
+                    seen.lines += 1;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!(format!("Failed to read dedup file {}: {}", path, e)),
+        }
+    }
+
+    seen
+}
+
+/// Appends the event to the file, rewriting it with the events remembered instead once it holds
+/// twice as many lines, so that it doesn't grow forever.
+fn persist(path: &str, seen: &mut Seen, key: &str, time: u64) -> std::io::Result<()> {
+    if seen.lines >= seen.events.cap().get() * 2 {
+        let content: String = seen
+            .events
+            .iter()
+            .rev()
+            .map(|event| format!("{}\n", json_line(event.0, *event.1)))
+            .collect();
+        let mut tmp = path.to_string();
+        tmp.push_str(".tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)?;
+        seen.lines = seen.events.len();
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", json_line(key, time))?;
+    seen.lines += 1;
+    Ok(())
+}
+
+fn json_line(key: &str, time: u64) -> String {
+    serde_json::to_string(&(key, time)).unwrap_or_default()
+}
+
+impl Deduplicator {
+    /// Reserves the event unless the notification has seen it within the window, returning whether
+    /// it's new.
+    ///
+    /// The reservation stops concurrent deliveries of the same event, and is either kept by
+    /// [`Deduplicator::remember`] or dropped by [`Deduplicator::release`] once the event is handled.
+    pub fn reserve(&self, id: &str, cfg: &DedupConfig, key: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let seen = seen.entry(id.to_string()).or_insert_with(|| load(cfg));
+
+        let now = now();
+        if seen
+            .events
+            .peek(key)
+            .is_some_and(|time| now.saturating_sub(*time) < cfg.window)
+        {
+            return false;
+        }
+        seen.events.put(key.to_string(), now);
+        true
+    }
+
+    /// Drops the reservation of an event which wasn't delivered, so that it's delivered on retry.
+    pub fn release(&self, id: &str, key: &str) {
+        if let Some(seen) = self.seen.lock().unwrap().get_mut(id) {
+            seen.events.pop(key);
+        }
+    }
+
+    /// Keeps the reservation of a delivered event, persisting it if the notification has a file.
+    pub fn remember(&self, id: &str, cfg: &DedupConfig, key: &str) {
+        let Some(path) = &cfg.persist else {
+            return;
+        };
+        let mut seen = self.seen.lock().unwrap();
+        let seen = seen.entry(id.to_string()).or_insert_with(|| load(cfg));
+        let time = *seen.events.get_or_insert(key.to_string(), now);
+
+        // Written while holding the lock, so that concurrent writes can't land out of order.
+        if let Err(e) = persist(path, seen, key, time) {
+            warn!(format!("Failed to write dedup file {}: {}", path, e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deduplicator;
+    use crate::config::DedupConfig;
+
+    #[test]
+    fn test_dedup() {
+        let path =
+            std::env::temp_dir().join(format!("notify-bot-dedup-{}.json", std::process::id()));
+        let cfg = DedupConfig {
+            window: 60,
+            key: None,
+            header: None,
+            capacity: Some(2),
+            persist: Some(path.to_string_lossy().to_string()),
+        };
+
+        let dedup = Deduplicator::default();
+        assert!(dedup.reserve("test", &cfg, "a"));
+        // Reserved while the first delivery is in flight
+        assert!(!dedup.reserve("test", &cfg, "a"));
+        dedup.remember("test", &cfg, "a");
+        assert!(!dedup.reserve("test", &cfg, "a"));
+        let other = DedupConfig {
+            persist: None,
+            ..cfg.clone()
+        };
+        assert!(dedup.reserve("other", &other, "a"));
+
+        assert!(dedup.reserve("test", &cfg, "x"));
+        dedup.release("test", "x");
+        assert!(dedup.reserve("test", &cfg, "x"));
+        dedup.release("test", "x");
+
+        for key in ["b", "c", "d", "c"] {
+            if dedup.reserve("test", &cfg, key) {
+                dedup.remember("test", &cfg, key);
+            }
+        }
+        assert!(dedup.reserve("test", &cfg, "a"));
+
+        let restarted = Deduplicator::default();
+        assert!(!restarted.reserve("test", &cfg, "c"));
+        assert!(!restarted.reserve("test", &cfg, "d"));
+        assert!(restarted.reserve("test", &cfg, "b"));
+        // Rewritten with the events remembered once it holds twice the capacity
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 4);
+        restarted.remember("test", &cfg, "b");
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::web::Data;
+use tklog::info;
+
+use crate::{
+    config::DigestConfig,
+    onebot::{self, Message},
+    service::{format_message, Sender},
+};
+
+const DEFAULT_MESSAGE: &str = "[{id}] {count} notifications:\n\n{events}";
+
+struct Batch {
+    // Tells the batches apart, so that a timer doesn't flush the batch after the one it was set for
+    seq: u64,
+    events: usize,
+    messages: Vec<Message>,
+}
+
+/// Messages buffered by the notifications in digest mode, keyed by the notification ID.
+#[derive(Default)]
+pub struct Digester {
+    batches: Mutex<HashMap<String, Batch>>,
+    seq: AtomicU64,
+}
+
+impl Digester {
+    /// Buffers the messages rendered from an event, and sends the digest within the limits of the
+    /// notification once the window elapses or enough events are buffered.
+    pub fn add(
+        digester: Data<Digester>,
+        cfg: &DigestConfig,
+        messages: Vec<Message>,
+        sender: Sender,
+    ) {
+        let id = sender.id.as_str();
+        let mut batches = digester.batches.lock().unwrap();
+        let batch = batches.entry(id.to_string()).or_insert_with(|| Batch {
+            seq: digester.seq.fetch_add(1, Ordering::Relaxed),
+            events: 0,
+            messages: vec![],
+        });
+        let first = batch.events == 0;
+        let seq = batch.seq;
+        batch.events += 1;
+        batch.messages.extend(messages);
+
+        if cfg.count.is_some_and(|count| batch.events >= count) {
+            let batch = batches.remove(id).unwrap();
+            drop(batches);
+            flush(cfg, batch.messages, &sender);
+            return;
+        }
+        drop(batches);
+
+        if !first {
+            return;
+        }
+
+        let id = id.to_string();
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(cfg.window)).await;
+
+            let mut batches = digester.batches.lock().unwrap();
+            if batches.get(&id).is_none_or(|batch| batch.seq != seq) {
+                return;
+            }
+            let batch = batches.remove(&id).unwrap();
+            drop(batches);
+            flush(&cfg, batch.messages, &sender);
+        });
+    }
+}
+
+fn flush(cfg: &DigestConfig, messages: Vec<Message>, sender: &Sender) {
+    info!(format!("Sending Digest of [{}]", sender.id));
+    sender.send(summarize(&sender.id, cfg, messages));
+}
+
+/// Renders the digest template once per target, listing the messages sent to it.
+fn summarize(id: &str, cfg: &DigestConfig, messages: Vec<Message>) -> Vec<Message> {
+    let template = cfg.message.as_deref().unwrap_or(DEFAULT_MESSAGE);
+    let sep = cfg.sep.as_deref().unwrap_or("\n\n");
+
+    onebot::group_by_target(messages)
+        .into_iter()
+        .map(|(msg, texts)| {
+            let contents = HashMap::from([
+                ("id".to_string(), id.to_string()),
+                ("count".to_string(), texts.len().to_string()),
+                ("events".to_string(), texts.join(sep)),
+            ]);
+            Message {
+                text: format_message(template, &contents),
+                ..msg
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::summarize;
+    use crate::{config::DigestConfig, onebot::Message};
+
+    #[test]
+    fn test_digest() {
+        let msg = |text: &str, users: &[&str]| Message {
+            text: text.to_string(),
+            groups: vec![],
+            users: users.iter().map(|u| u.to_string()).collect(),
+            mentions: vec![],
+        };
+        let cfg = DigestConfig {
+            window: 300,
+            count: None,
+            message: Some("{count} from {id}: {events}".to_string()),
+            sep: Some(", ".to_string()),
+        };
+
+        let digest = summarize(
+            "ci",
+            &cfg,
+            vec![msg("a", &["1"]), msg("b", &["1", "2"]), msg("c", &["1"])],
+        );
+        assert_eq!(digest.len(), 2);
+        assert_eq!(digest[0].users, vec!["1"]);
+        assert_eq!(digest[0].text, "3 from ci: a, b, c");
+        assert_eq!(digest[1].users, vec!["2"]);
+        assert_eq!(digest[1].text, "1 from ci: b");
+    }
+}
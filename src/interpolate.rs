@@ -0,0 +1,251 @@
+use std::{env, fs};
+
+use serde_json::{Map, Value};
+
+/// Templates filled with the extracted contents, whose `${` is left as it is.
+const TEMPLATES: [&str; 7] = [
+    "notifications[].message",
+    "notifications[].resolved_message",
+    "notifications[].dedup.key",
+    "notifications[].digest.message",
+    "notifications[].auth.payload",
+    "presets[].message",
+    "presets[].auth.payload",
+];
+
+/// Secrets which may be read from a file with the `_file` suffix instead, where `*` stands for any
+/// key, such as the name of a header.
+const SECRETS: [&str; 5] = [
+    "notifications[].token",
+    "notifications[].tokens[].token",
+    "admin.token",
+    "onebot.url",
+    "pollers[].headers.*",
+];
+
+fn is_secret(field: &str) -> bool {
+    SECRETS.iter().any(|secret| match secret.strip_suffix('*') {
+        Some(parent) => field
+            .strip_prefix(parent)
+            .is_some_and(|key| !key.is_empty() && !key.contains('.')),
+        None => *secret == field,
+    })
+}
+
+/// Resolves `${VAR}` and `${VAR:-default}` in the strings of the config except the templates, and
+/// replaces the secrets suffixed with `_file`, such as `token_file`, with the content of the file.
+///
+/// `at` is where the value is in the config, e.g. `notifications` for an array of notifications.
+/// Returns the problems found, along with the path of the field.
+pub fn resolve(value: &mut Value, at: &str) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    resolve_impl(value, "", at, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Resolves the value at the path, whose `field` has the indices left out, e.g. `notifications[].token`.
+fn resolve_impl(value: &mut Value, path: &str, field: &str, errors: &mut Vec<String>) {
+    match value {
+        Value::String(s) if !TEMPLATES.contains(&field) => match interpolate(s) {
+            Ok(res) => *s = res,
+            Err(e) => errors.push(format!("{}: {}", path, e)),
+        },
+        Value::Array(arr) => {
+            for (i, v) in arr.iter_mut().enumerate() {
+                resolve_impl(
+                    v,
+                    &format!("{}[{}]", path, i),
+                    &format!("{}[]", field),
+                    errors,
+                );
+            }
+        }
+        Value::Object(obj) => {
+            for (key, v) in obj.iter_mut() {
+                resolve_impl(v, &join(path, key), &join(field, key), errors);
+            }
+            read_files(obj, path, field, errors);
+        }
+        _ => {}
+    }
+}
+
+/// Replaces the secrets in the value with a placeholder, `at` being where it is in the config.
+pub fn redact(value: &mut Value, at: &str) {
+    match value {
+        Value::String(s) if is_secret(at) => *s = "<redacted>".to_string(),
+        Value::Array(arr) => {
+            for v in arr.iter_mut() {
+                redact(v, &format!("{}[]", at));
+            }
+        }
+        Value::Object(obj) => {
+            for (key, v) in obj.iter_mut() {
+                redact(v, &join(at, key));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn read_files(obj: &mut Map<String, Value>, path: &str, field: &str, errors: &mut Vec<String>) {
+    let files: Vec<String> = obj
+        .iter()
+        .filter(|(key, v)| {
+            key.strip_suffix("_file")
+                .is_some_and(|secret| is_secret(&join(field, secret)))
+                && v.is_string()
+        })
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    for key in files {
+        let secret = key.trim_end_matches("_file").to_string();
+        let at = join(path, &key);
+        if obj.contains_key(&secret) {
+            errors.push(format!("{}: {} is specified as well", at, secret));
+            continue;
+        }
+
+        let file = obj.remove(&key).unwrap_or_default();
+        let file = file.as_str().unwrap_or_default();
+        match fs::read_to_string(file) {
+            Ok(content) => {
+                let content = content.trim_end_matches(['\r', '\n']).to_string();
+                obj.insert(secret, Value::String(content));
+            }
+            Err(e) => errors.push(format!("{}: failed to read {}: {}", at, file, e)),
+        }
+    }
+}
+
+/// Replaces the variables in the string, keeping `$${` as a literal `${`.
+fn interpolate(s: &str) -> Result<String, String> {
+    let mut res = String::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find('$') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            res.push_str("${");
+            rest = escaped;
+        } else if let Some(var) = rest.strip_prefix("${") {
+            let end = var.find('}').ok_or(format!("unclosed variable in {}", s))?;
+            let (name, default) = match var[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&var[..end], None),
+            };
+            // Only the default form treats an empty variable as unset
+            match (env::var(name).ok(), default) {
+                (Some(value), Some(default)) if value.is_empty() => res.push_str(default),
+                (Some(value), _) => res.push_str(&value),
+                (None, Some(default)) => res.push_str(default),
+                (None, None) => return Err(format!("environment variable {} is not set", name)),
+            }
+            rest = &var[end + 1..];
+        } else {
+            res.push('$');
+            rest = &rest[1..];
+        }
+    }
+    res.push_str(rest);
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{redact, resolve};
+
+    #[test]
+    fn test_resolve() {
+        let secret = std::env::temp_dir().join(format!("notify-bot-secret-{}", std::process::id()));
+        std::fs::write(&secret, "s3cret\n").unwrap();
+        std::env::set_var("NOTIFY_BOT_TEST_URL", "http://onebot:3000");
+        std::env::set_var("NOTIFY_BOT_TEST_EMPTY", "");
+
+        let mut conf = json!({
+            "onebot": { "url": "${NOTIFY_BOT_TEST_URL}/api" },
+            "notifications": [{
+                "id": "${NOTIFY_BOT_TEST_UNSET:-a}",
+                "token_file": secret.to_string_lossy(),
+                "users": ["$${literal}", "${NOTIFY_BOT_TEST_EMPTY}", "${NOTIFY_BOT_TEST_EMPTY:-b}"],
+                "message": "${price} costs $5",
+                "groups": [1]
+            }],
+            "schedules": [{ "body": { "path_file": "/nonexistent" } }]
+        });
+        resolve(&mut conf, "").unwrap();
+        assert_eq!(
+            conf,
+            json!({
+                "onebot": { "url": "http://onebot:3000/api" },
+                "notifications": [{
+                    "id": "a",
+                    "token": "s3cret",
+                    "users": ["${literal}", "", "b"],
+                    "message": "${price} costs $5",
+                    "groups": [1]
+                }],
+                "schedules": [{ "body": { "path_file": "/nonexistent" } }]
+            })
+        );
+
+        let mut conf = json!({
+            "onebot": { "url": "${NOTIFY_BOT_TEST_UNSET}" },
+            "notifications": [{ "token": "a", "token_file": "/nonexistent" }]
+        });
+        assert_eq!(
+            resolve(&mut conf, "").unwrap_err(),
+            vec![
+                "notifications[0].token_file: token is specified as well",
+                "onebot.url: environment variable NOTIFY_BOT_TEST_UNSET is not set",
+            ]
+        );
+
+        let mut conf = json!({
+            "onebot": { "url_file": secret.to_string_lossy() },
+            "pollers": [{ "headers": { "Authorization_file": secret.to_string_lossy() } }]
+        });
+        resolve(&mut conf, "").unwrap();
+        assert_eq!(
+            conf,
+            json!({
+                "onebot": { "url": "s3cret" },
+                "pollers": [{ "headers": { "Authorization": "s3cret" } }]
+            })
+        );
+
+        let mut notification =
+            json!({ "id": "a", "tokens": [{ "token_file": secret.to_string_lossy() }] });
+        resolve(&mut notification, "notifications[]").unwrap();
+        assert_eq!(
+            notification,
+            json!({ "id": "a", "tokens": [{ "token": "s3cret" }] })
+        );
+
+        redact(&mut notification, "notifications[]");
+        assert_eq!(
+            notification,
+            json!({ "id": "a", "tokens": [{ "token": "<redacted>" }] })
+        );
+
+        let _ = std::fs::remove_file(secret);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::web::Data;
+use tklog::info;
+
+use crate::{
+    config::{BucketConfig, RateLimitAction, RateLimitConfig},
+    onebot::{self, Message, Target},
+    service::Sender,
+};
+
+/// Buckets are pruned once there are this many of them, keeping the ones not refilled yet.
+const MAX_BUCKETS: usize = 10000;
+
+/// The limit exceeded by an event or a message, and what to do with it.
+pub struct Exceeded {
+    pub action: RateLimitAction,
+    pub wait: Duration,
+}
+
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    // Tokens refilled per second
+    refill: f64,
+    updated: Instant,
+}
+
+/// Token buckets shared by all the workers, and the messages held back by coalescing limits.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    pending: Mutex<HashMap<String, Vec<Message>>>,
+}
+
+impl RateLimiter {
+    /// Takes a token from each of the buckets, or none of them if any is empty, in which case the
+    /// first limit exceeded is returned, along with how long it takes to refill the buckets.
+    fn acquire(
+        &self,
+        buckets: &[(String, &BucketConfig, &RateLimitConfig)],
+    ) -> Result<(), Exceeded> {
+        let now = Instant::now();
+        let refill =
+            |cfg: &BucketConfig| cfg.rate.max(1) as f64 / cfg.per.unwrap_or(60).max(1) as f64;
+
+        let mut state = self.buckets.lock().unwrap();
+        if state.len() >= MAX_BUCKETS {
+            // Buckets of different configs share the map, so the rate of each is kept with it
+            state.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * b.refill < b.capacity
+            });
+        }
+
+        let mut exceeded: Option<Exceeded> = None;
+        for (key, cfg, limit) in buckets {
+            let capacity = cfg.burst.unwrap_or(cfg.rate).max(1) as f64;
+            let refill = refill(cfg);
+            let bucket = state.entry(key.clone()).or_insert(Bucket {
+                tokens: capacity,
+                capacity,
+                refill,
+                updated: now,
+            });
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.updated).as_secs_f64() * refill)
+                .min(capacity);
+            bucket.capacity = capacity;
+            bucket.refill = refill;
+            bucket.updated = now;
+
+            if bucket.tokens < 1.0 {
+                let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / refill);
+                match &mut exceeded {
+                    Some(exceeded) => exceeded.wait = exceeded.wait.max(wait),
+                    None => exceeded = Some(Self::exceeded(limit, wait)),
+                }
+            }
+        }
+        if let Some(exceeded) = exceeded {
+            return Err(exceeded);
+        }
+
+        for (key, _, _) in buckets {
+            if let Some(bucket) = state.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    fn exceeded(cfg: &RateLimitConfig, wait: Duration) -> Exceeded {
+        Exceeded {
+            action: cfg.action.unwrap_or(RateLimitAction::Reject),
+            wait,
+        }
+    }
+
+    /// Applies the total and the per address limits of the scopes to an incoming event.
+    ///
+    /// Tokens are only taken if none of the limits is exceeded, so that events rejected by one limit
+    /// don't drain the others.
+    pub fn check_event(
+        &self,
+        limits: &[(String, RateLimitConfig)],
+        ip: Option<IpAddr>,
+    ) -> Result<(), Exceeded> {
+        let ip = ip.map_or("unknown".to_string(), |ip| ip.to_string());
+        let mut buckets = vec![];
+        for (scope, cfg) in limits {
+            if let Some(total) = &cfg.total {
+                buckets.push((format!("{}/total", scope), total, cfg));
+            }
+            if let Some(per_ip) = &cfg.ip {
+                buckets.push((format!("{}/ip/{}", scope, ip), per_ip, cfg));
+            }
+        }
+        self.acquire(&buckets)
+    }
+
+    /// Applies the per target limits of the scopes to an outgoing message.
+    pub fn check_target(
+        &self,
+        limits: &[(String, RateLimitConfig)],
+        target: &Target,
+    ) -> Result<(), Exceeded> {
+        let buckets: Vec<_> = limits
+            .iter()
+            .filter_map(|(scope, cfg)| {
+                cfg.target
+                    .as_ref()
+                    .map(|per_target| (format!("{}/target/{}", scope, target), per_target, cfg))
+            })
+            .collect();
+        self.acquire(&buckets)
+    }
+
+    /// Holds the messages back, then sends all the messages held for the key at once after the delay,
+    /// as a single summary per target, within the per target limits.
+    pub fn coalesce(
+        limiter: Data<RateLimiter>,
+        key: &str,
+        messages: Vec<Message>,
+        wait: Duration,
+        sender: Sender,
+    ) {
+        let mut pending = limiter.pending.lock().unwrap();
+        let held = pending.entry(key.to_string()).or_default();
+        let first = held.is_empty();
+        held.extend(messages);
+        drop(pending);
+
+        if !first {
+            return;
+        }
+
+        let key = key.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+
+            let held = limiter
+                .pending
+                .lock()
+                .unwrap()
+                .remove(&key)
+                .unwrap_or_default();
+            info!("Sending Coalesced Messages");
+            sender.send(summarize(held));
+        });
+    }
+}
+
+/// Merges the messages into one per target.
+fn summarize(messages: Vec<Message>) -> Vec<Message> {
+    onebot::group_by_target(messages)
+        .into_iter()
+        .map(|(msg, texts)| {
+            let text = if texts.len() == 1 {
+                texts[0].clone()
+            } else {
+                format!(
+                    "{} notifications were held back by the rate limit:\n\n{}",
+                    texts.len(),
+                    texts.join("\n\n")
+                )
+            };
+            Message { text, ..msg }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{summarize, RateLimiter};
+    use crate::{
+        config::{BucketConfig, RateLimitConfig},
+        onebot::{Message, Target},
+    };
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::default();
+        let cfg = RateLimitConfig {
+            total: None,
+            ip: None,
+            target: Some(BucketConfig {
+                rate: 1,
+                per: Some(3600),
+                burst: Some(2),
+            }),
+            action: None,
+        };
+        let limits = |scope: &str| vec![(scope.to_string(), cfg.clone())];
+
+        let group = Target::Group("1".to_string());
+        let user = Target::User("1".to_string());
+        assert!(limiter.check_target(&limits("test"), &group).is_ok());
+        assert!(limiter.check_target(&limits("test"), &group).is_ok());
+        let exceeded = limiter.check_target(&limits("test"), &group).err().unwrap();
+        assert!(exceeded.wait.as_secs() > 3500);
+        assert!(limiter.check_target(&limits("test"), &user).is_ok());
+        assert!(limiter.check_target(&limits("other"), &group).is_ok());
+    }
+
+    #[test]
+    fn test_event_limits() {
+        let limiter = RateLimiter::default();
+        let bucket = |rate: u32| BucketConfig {
+            rate,
+            per: Some(3600),
+            burst: None,
+        };
+        let server = RateLimitConfig {
+            total: Some(bucket(3)),
+            ip: Some(bucket(1)),
+            target: None,
+            action: None,
+        };
+        let notify = RateLimitConfig {
+            total: Some(bucket(2)),
+            ip: None,
+            target: None,
+            action: None,
+        };
+        let limits = vec![("server".to_string(), server), ("a".to_string(), notify)];
+        let ip = |s: &str| s.parse().ok();
+
+        assert!(limiter.check_event(&limits, ip("1.1.1.1")).is_ok());
+        // Rejected events of an address don't take the tokens of the total limits
+        for _ in 0..3 {
+            assert!(limiter.check_event(&limits, ip("1.1.1.1")).is_err());
+        }
+        assert!(limiter.check_event(&limits, ip("2.2.2.2")).is_ok());
+        // The total limit of the notification is exceeded, leaving one token for the server
+        assert!(limiter.check_event(&limits, ip("3.3.3.3")).is_err());
+        assert!(limiter.check_event(&limits[..1], ip("3.3.3.3")).is_ok());
+    }
+
+    #[test]
+    fn test_summarize() {
+        let msg = |text: &str, groups: &[&str]| Message {
+            text: text.to_string(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            users: vec![],
+            mentions: vec![],
+        };
+
+        let summary = summarize(vec![msg("a", &["1", "2"]), msg("b", &["1"])]);
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].groups, vec!["1"]);
+        assert_eq!(
+            summary[0].text,
+            "2 notifications were held back by the rate limit:\n\na\n\nb"
+        );
+        assert_eq!(summary[1].groups, vec!["2"]);
+        assert_eq!(summary[1].text, "a");
+    }
+}
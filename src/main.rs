@@ -1,22 +1,47 @@
 #![recursion_limit = "10240"]
+// tklog exposes its logger as a `const Lazy`, which trips this lint on every use.
+#![allow(clippy::borrow_interior_mutable_const)]
 
+mod access;
+mod admin;
+mod auth;
+mod check;
+mod cli;
 mod config;
+mod dedup;
+mod digest;
+mod interpolate;
+mod limit;
+mod onebot;
+mod poll;
+mod preset;
+mod quiet;
+mod reload;
+mod schedule;
 mod service;
+mod tls;
 
 use std::{
-    collections::HashMap,
     fs::{self},
-    io::Result,
+    io::{stdin, Result},
     path::Path,
+    sync::Arc,
     time::Duration,
 };
 
 use actix_web::{web::Data, App, HttpServer};
-use clap::{command, Parser};
-use config::{Config, LogConfig, RuntimeConfig};
+use admin::Admin;
+use arc_swap::ArcSwap;
+use auth::HashAlgorithm;
+use clap::{Parser, Subcommand};
+use config::{LogConfig, LogLevel, Overrides};
+use dedup::Deduplicator;
+use digest::Digester;
+use limit::RateLimiter;
+use reload::Reloader;
 use reqwest::Client;
-use service::notify;
-use tklog::{info, warn, Format, LEVEL, LOG};
+use service::{notify, preview};
+use tklog::{error, info, Format, LEVEL, LOG};
 
 use shadow_rs::shadow;
 
@@ -27,12 +52,105 @@ shadow!(build);
 /// A simple notification server for QQBot.
 struct Args {
     /// The config file path.
-    #[arg(short, long)]
+    #[arg(short, long, env = "NOTIFY_BOT_CONFIG", global = true)]
     config: Option<String>,
+
+    #[command(flatten)]
+    overrides: Overrides,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Hash a token to be stored in the config file instead of the token itself.
+    HashToken {
+        /// The token to be hashed. Read from stdin if not specified.
+        token: Option<String>,
+
+        /// The hashing algorithm.
+        #[arg(short, long, value_enum, default_value_t = HashAlgorithm::Argon2)]
+        algorithm: HashAlgorithm,
+    },
+
+    /// Run the server. The default if no command is given.
+    Serve,
+
+    /// Check the config file, reporting the errors and warnings found.
+    Check,
+
+    /// Render a notification from a request body and send it right away.
+    Send {
+        /// ID of the notification.
+        id: String,
+
+        /// The JSON file of the request body, or - for stdin. Defaults to {}.
+        #[arg(short, long)]
+        body: Option<String>,
+    },
+
+    /// Print the messages a notification renders from a request body, without sending them.
+    Render {
+        /// ID of the notification.
+        id: String,
+
+        /// The JSON file of the request body, or - for stdin. Defaults to {}.
+        #[arg(short, long)]
+        body: Option<String>,
+    },
+
+    /// List the notifications with their targets and endpoints.
+    List,
+}
+
+/// The first config found in the working directory, preferring JSON.
+fn default_config() -> String {
+    ["json", "jsonc", "json5", "yaml", "yml", "toml"]
+        .iter()
+        .map(|ext| format!("./config.{}", ext))
+        .find(|path| Path::new(path).exists())
+        .unwrap_or("./config.json".to_string())
 }
 
 #[actix_web::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    match args.command {
+        Some(Command::HashToken { token, algorithm }) => {
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    let mut token = String::new();
+                    stdin().read_line(&mut token)?;
+                    token.trim_end_matches(['\r', '\n']).to_string()
+                }
+            };
+            println!("{}", auth::hash_token(&token, algorithm));
+            return Ok(());
+        }
+        Some(Command::Check) => {
+            cli::check(&args.config.unwrap_or_else(default_config), &args.overrides);
+            return Ok(());
+        }
+        Some(Command::Send { id, body }) => {
+            let conf_path = args.config.unwrap_or_else(default_config);
+            cli::send(&conf_path, &args.overrides, &id, body.as_deref()).await;
+            return Ok(());
+        }
+        Some(Command::Render { id, body }) => {
+            let conf_path = args.config.unwrap_or_else(default_config);
+            cli::render(&conf_path, &args.overrides, &id, body.as_deref());
+            return Ok(());
+        }
+        Some(Command::List) => {
+            cli::list(&args.config.unwrap_or_else(default_config), &args.overrides);
+            return Ok(());
+        }
+        Some(Command::Serve) | None => {}
+    }
+
     println!(
         r"[38;2;99;35;249m [39m[38;2;95;37;250m [39m[38;2;91;41;251m [39m[38;2;87;44;252m [39m[38;2;83;47;253m_[39m[38;2;79;50;253m [39m[38;2;75;54;254m [39m[38;2;71;57;254m [39m[38;2;67;61;254m_[39m[38;2;64;64;254m_[39m[38;2;60;68;254m [39m[38;2;57;72;254m [39m[38;2;53;76;254m [39m[38;2;50;79;253m [39m[38;2;46;83;253m [39m[38;2;43;87;252m [39m[38;2;40;91;251m_[39m[38;2;37;96;250m_[39m[38;2;34;100;249m [39m[38;2;31;104;247m [39m[38;2;29;108;246m_[39m[38;2;26;112;244m [39m[38;2;23;116;243m_[39m[38;2;21;121;241m_[39m[38;2;19;125;239m_[39m[38;2;17;129;237m_[39m[38;2;15;133;234m [39m[38;2;13;138;232m [39m[38;2;11;142;230m [39m[38;2;9;146;227m [39m[38;2;8;150;224m [39m[38;2;7;154;222m [39m[38;2;5;158;219m [39m[38;2;4;162;216m [39m[38;2;3;167;213m [39m[38;2;3;171;209m [39m[38;2;2;174;206m [39m[38;2;1;178;203m [39m[38;2;1;182;199m_[39m[38;2;1;186;196m_[39m[38;2;1;190;192m_[39m[38;2;1;193;189m_[39m[38;2;1;197;185m [39m[38;2;1;200;181m [39m[38;2;1;204;177m [39m[38;2;2;207;173m [39m[38;2;3;211;169m [39m[38;2;4;214;165m [39m[38;2;5;217;161m [39m[38;2;6;220;157m [39m[38;2;7;223;153m_[39m[38;2;8;225;149m_[39m[38;2;10;228;145m [39m
 [39m[38;2;95;37;250m [39m[38;2;91;41;251m [39m[38;2;87;44;252m [39m[38;2;83;47;253m/[39m[38;2;79;50;253m [39m[38;2;75;54;254m|[39m[38;2;71;57;254m [39m[38;2;67;61;254m/[39m[38;2;64;64;254m [39m[38;2;60;68;254m/[39m[38;2;57;72;254m_[39m[38;2;53;76;254m_[39m[38;2;50;79;253m_[39m[38;2;46;83;253m [39m[38;2;43;87;252m [39m[38;2;40;91;251m/[39m[38;2;37;96;250m [39m[38;2;34;100;249m/[39m[38;2;31;104;247m_[39m[38;2;29;108;246m([39m[38;2;26;112;244m_[39m[38;2;23;116;243m)[39m[38;2;21;121;241m [39m[38;2;19;125;239m_[39m[38;2;17;129;237m_[39m[38;2;15;133;234m/[39m[38;2;13;138;232m_[39m[38;2;11;142;230m [39m[38;2;9;146;227m [39m[38;2;8;150;224m_[39m[38;2;7;154;222m_[39m[38;2;5;158;219m [39m[38;2;4;162;216m [39m[38;2;3;167;213m [39m[38;2;3;171;209m [39m[38;2;2;174;206m [39m[38;2;1;178;203m [39m[38;2;1;182;199m/[39m[38;2;1;186;196m [39m[38;2;1;190;192m_[39m[38;2;1;193;189m_[39m[38;2;1;197;185m [39m[38;2;1;200;181m)[39m[38;2;1;204;177m_[39m[38;2;2;207;173m_[39m[38;2;3;211;169m_[39m[38;2;4;214;165m_[39m[38;2;5;217;161m [39m[38;2;6;220;157m [39m[38;2;7;223;153m/[39m[38;2;8;225;149m [39m[38;2;10;228;145m/[39m[38;2;12;230;140m_[39m
@@ -45,8 +163,6 @@ async fn main() -> Result<()> {
 
     println!("Version {}", build::PKG_VERSION);
 
-    let args = Args::parse();
-
     LOG.set_console(true)
         .set_level(LEVEL::Info)
         .set_format(Format::LevelFlag | Format::Time | Format::ShortFileName)
@@ -80,8 +196,8 @@ async fn main() -> Result<()> {
     let conf_path = if let Some(path) = args.config {
         path
     } else {
-        println!("No Config File Specified. Looking for ./config.json...");
-        "./config.json".to_string()
+        println!("No Config File Specified. Looking for ./config.json, or .jsonc, .json5, .yaml, .yml and .toml...");
+        default_config()
     };
     let conf_path = Path::new(&conf_path);
 
@@ -105,18 +221,13 @@ async fn main() -> Result<()> {
         conf_path.canonicalize().unwrap().display()
     );
 
-    let conf = fs::read_to_string(conf_path);
-    if let Err(e) = conf {
-        println!("\x1b[31mFailed to read config file: {}\x1b[0m", e);
-        return Ok(());
-    }
-
-    let conf = Config::parse(conf.ok().unwrap());
-    if let Err(e) = conf {
-        println!("\x1b[31mFailed to parse config file: {}\nMight be caused by incorrect json syntax or missing fields.\x1b[0m", e);
-        return Ok(());
-    }
-    let conf = conf.ok().unwrap();
+    let (conf, raw, report) = match reload::read(conf_path, &args.overrides) {
+        Ok(res) => res,
+        Err(report) => {
+            report.print();
+            return Ok(());
+        }
+    };
 
     println!();
 
@@ -127,41 +238,107 @@ async fn main() -> Result<()> {
         log_conf.backup.unwrap_or(1),
         log_conf.compress.unwrap_or(true),
     );
+    LOG.set_level(match log_conf.level.unwrap_or(LogLevel::Info) {
+        LogLevel::Trace => LEVEL::Trace,
+        LogLevel::Debug => LEVEL::Debug,
+        LogLevel::Info => LEVEL::Info,
+        LogLevel::Warn => LEVEL::Warn,
+        LogLevel::Error => LEVEL::Error,
+    });
 
+    report.log();
     info!("Config Loaded");
 
     let ip = conf.server.ip.clone();
     let port = conf.server.port;
-    info!(format!("Server Listening at {}:{}", ip, port));
+    if conf.server.tls.is_some() {
+        info!(format!("Server Listening at https://{}:{}", ip, port));
+    } else {
+        info!(format!("Server Listening at {}:{}", ip, port));
+    }
 
-    let server = HttpServer::new(move || {
-        let mut notifications = HashMap::new();
-        for n in &conf.notifications {
-            notifications.insert(n.id.clone(), n.clone());
-            if n.groups.is_none() && n.users.is_none() {
-                warn!(format!(
-                    "Notification with ID [{}] has no group or person specified. It won't take any effect.",
-                    n.id
-                ));
-            }
-        }
+    let runtime_conf = Data::new(ArcSwap::from_pointee(reload::build(&conf)));
+
+    let limiter = Data::new(RateLimiter::default());
+    let dedup = Data::new(Deduplicator::default());
+    let digester = Data::new(Digester::default());
 
-        let runtime_conf = RuntimeConfig {
-            onebot: conf.onebot.clone(),
-            notifications,
-        };
+    // Shared by the workers, schedules and pollers
+    let client = Client::builder()
+        .timeout(Duration::from_secs(1))
+        .build()
+        .unwrap();
 
-        let client = Client::builder()
-            .timeout(Duration::from_secs(1))
-            .build()
-            .unwrap();
+    let reloader = Arc::new(Reloader::new(
+        conf_path,
+        args.overrides.clone(),
+        runtime_conf.clone(),
+        raw,
+        client.clone(),
+        limiter.clone(),
+        digester.clone(),
+    ));
+    reloader.clone().watch(conf.watch.unwrap_or(false));
 
+    // Served on the address of the server unless a port is given
+    let admin = Data::new(Admin::new(reloader));
+    let admin_addr = conf.admin.as_ref().and_then(|a| {
+        a.port
+            .map(|port| (a.ip.clone().unwrap_or("127.0.0.1".to_string()), port))
+    });
+    let admin_server = match admin_addr.clone() {
+        Some((admin_ip, admin_port)) => {
+            info!(format!(
+                "Admin API Listening at {}:{}",
+                admin_ip, admin_port
+            ));
+            let runtime_conf = runtime_conf.clone();
+            let admin = admin.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .configure(admin::routes)
+                    .app_data(runtime_conf.clone())
+                    .app_data(admin.clone())
+            })
+            .workers(1)
+            .bind((admin_ip, admin_port))?;
+            Some(server.run())
+        }
+        None => None,
+    };
+
+    let server = HttpServer::new(move || {
         App::new()
             .service(notify)
-            .app_data(Data::new(runtime_conf))
-            .app_data(Data::new(client))
+            .service(preview)
+            .configure(|cfg| {
+                if admin_addr.is_none() {
+                    admin::routes(cfg);
+                }
+            })
+            .app_data(runtime_conf.clone())
+            .app_data(admin.clone())
+            .app_data(Data::new(client.clone()))
+            .app_data(limiter.clone())
+            .app_data(dedup.clone())
+            .app_data(digester.clone())
     })
-    .bind((ip, port))?
-    .run();
-    server.await
+    .on_connect(tls::on_connect);
+
+    let server = if let Some(tls_conf) = &conf.server.tls {
+        match tls::server_config(tls_conf) {
+            Ok(tls_conf) => server.bind_rustls_0_23((ip, port), tls_conf)?,
+            Err(e) => {
+                error!(format!("Failed to set up TLS: {}", e));
+                return Ok(());
+            }
+        }
+    } else {
+        server.bind((ip, port))?
+    };
+
+    match admin_server {
+        Some(admin_server) => futures::try_join!(server.run(), admin_server).map(|_| ()),
+        None => server.run().await,
+    }
 }
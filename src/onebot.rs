@@ -0,0 +1,158 @@
+use std::fmt;
+
+use reqwest::Client;
+use serde_json::{json, Value};
+
+use crate::config::{NotifyConfig, OneBotConfig};
+
+/// A group or a user receiving messages.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Group(String),
+    User(String),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Group(id) => write!(f, "group:{}", id),
+            Target::User(id) => write!(f, "user:{}", id),
+        }
+    }
+}
+
+/// A rendered message together with the targets it should be delivered to.
+#[derive(Clone)]
+pub struct Message {
+    pub text: String,
+    pub groups: Vec<String>,
+    pub users: Vec<String>,
+    pub mentions: Vec<String>,
+}
+
+impl Message {
+    /// Creates a message addressed to the default targets of the notification.
+    pub fn new(text: String, cfg: &NotifyConfig) -> Message {
+        Message {
+            text,
+            groups: cfg.groups.clone().unwrap_or_default(),
+            users: cfg.users.clone().unwrap_or_default(),
+            mentions: cfg.mentions.clone().unwrap_or_default(),
+        }
+    }
+
+    pub fn targets(&self) -> Vec<Target> {
+        self.groups
+            .iter()
+            .map(|g| Target::Group(g.clone()))
+            .chain(self.users.iter().map(|u| Target::User(u.clone())))
+            .collect()
+    }
+
+    /// A copy of the message addressed to the target only.
+    pub fn to(&self, target: &Target) -> Message {
+        let (groups, users) = match target {
+            Target::Group(id) => (vec![id.clone()], vec![]),
+            Target::User(id) => (vec![], vec![id.clone()]),
+        };
+        Message {
+            text: self.text.clone(),
+            groups,
+            users,
+            mentions: self.mentions.clone(),
+        }
+    }
+
+    /// Builds the OneBot actions and their payloads for every target of the message.
+    pub fn payloads(&self) -> Vec<(&'static str, Value)> {
+        let mut payloads = vec![];
+
+        for person in &self.users {
+            payloads.push((
+                "send_private_msg",
+                json!({
+                    "user_id": person,
+                    "message": [
+                        {
+                            "type": "text",
+                            "data": {
+                                "text": self.text
+                            }
+                        }
+                    ]
+                }),
+            ));
+        }
+
+        let mut group_text = self.text.clone();
+        if !self.mentions.is_empty() {
+            group_text = format!("{}\n", group_text);
+            for mention in &self.mentions {
+                group_text = format!("{} [CQ:at,qq={}]", group_text, mention);
+            }
+        }
+
+        for group in &self.groups {
+            payloads.push((
+                "send_group_msg",
+                json!({
+                    "group_id": group,
+                    "message": [
+                        {
+                            "type": "text",
+                            "data": {
+                                "text": group_text
+                            }
+                        }
+                    ]
+                }),
+            ));
+        }
+
+        payloads
+    }
+}
+
+/// Groups the texts of the messages by target, keeping the first message addressed to each target.
+pub fn group_by_target(messages: Vec<Message>) -> Vec<(Message, Vec<String>)> {
+    let mut targets: Vec<(Target, Message, Vec<String>)> = vec![];
+
+    for msg in messages {
+        for target in msg.targets() {
+            match targets.iter_mut().find(|(t, _, _)| *t == target) {
+                Some((_, _, texts)) => texts.push(msg.text.clone()),
+                None => targets.push((target.clone(), msg.to(&target), vec![msg.text.clone()])),
+            }
+        }
+    }
+
+    targets
+        .into_iter()
+        .map(|(_, msg, texts)| (msg, texts))
+        .collect()
+}
+
+pub fn send(client: &Client, onebot: &OneBotConfig, msg: &Message) {
+    for (action, payload) in msg.payloads() {
+        tokio::spawn(
+            client
+                .post(format!("{}/{}", onebot.url, action))
+                .body(payload.to_string())
+                .send(),
+        );
+    }
+}
+
+/// Sends the message and waits for the OneBot server to accept it.
+pub async fn deliver(client: &Client, onebot: &OneBotConfig, msg: &Message) -> Result<(), String> {
+    for (action, payload) in msg.payloads() {
+        client
+            .post(format!("{}/{}", onebot.url, action))
+            .body(payload.to_string())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use std::{collections::HashMap, time::Duration};
+
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use reqwest::Client;
+use serde_json::{json, Value};
+use tklog::{info, warn};
+use tokio::task::JoinHandle;
+
+use crate::{
+    config::{PollerConfig, RuntimeConfig},
+    digest::Digester,
+    limit::RateLimiter,
+    service,
+};
+
+/// Spawns a task for every poller of the config, sending its notification whenever the extracted
+/// contents change.
+pub fn start(
+    conf: Data<ArcSwap<RuntimeConfig>>,
+    client: &Client,
+    limiter: Data<RateLimiter>,
+    digester: Data<Digester>,
+) -> Vec<JoinHandle<()>> {
+    let runtime_cfg = conf.load();
+    let mut tasks = vec![];
+
+    for cfg in &runtime_cfg.pollers {
+        if !runtime_cfg.notifications.contains_key(&cfg.notification) {
+            warn!(format!(
+                "Poller of [{}] sends an unknown notification [{}]. It won't take any effect.",
+                cfg.url, cfg.notification
+            ));
+            continue;
+        }
+
+        tasks.push(tokio::spawn(run(
+            cfg.clone(),
+            conf.clone(),
+            client.clone(),
+            limiter.clone(),
+            digester.clone(),
+        )));
+    }
+
+    tasks
+}
+
+async fn run(
+    cfg: PollerConfig,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+    client: Client,
+    limiter: Data<RateLimiter>,
+    digester: Data<Digester>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(cfg.interval.max(1)));
+    let mut snapshot: Option<HashMap<String, String>> = None;
+
+    loop {
+        interval.tick().await;
+
+        let contents = match fetch(&cfg, &client).await {
+            Ok(val) => service::extract_contents(&val, &cfg.extractors),
+            Err(e) => {
+                warn!(format!("Failed to poll {}: {}", cfg.url, e));
+                continue;
+            }
+        };
+
+        // The first fetch only takes the snapshot
+        let Some(old) = snapshot.replace(contents.clone()) else {
+            continue;
+        };
+        let changed = changes(&cfg, &old, &contents);
+        if changed.is_empty() {
+            continue;
+        }
+
+        let runtime_cfg = conf.load_full();
+        let Some(notify_cfg) = runtime_cfg.notifications.get(&cfg.notification) else {
+            warn!(format!(
+                "Poller of [{}] sends an unknown notification [{}]. Skipped.",
+                cfg.url, cfg.notification
+            ));
+            continue;
+        };
+        if !notify_cfg.is_enabled() {
+            info!(format!(
+                "Poller of [{}] sends a disabled notification [{}]. Skipped.",
+                cfg.url, cfg.notification
+            ));
+            continue;
+        }
+        info!(format!(
+            "Poller of [{}] -> /notify-{}, changed: {}",
+            cfg.url,
+            cfg.notification,
+            changed.join(", ")
+        ));
+        let body = json!({
+            "url": cfg.url,
+            "changed": changed,
+            "old": old,
+            "new": contents,
+        });
+        service::handle_notify_request(
+            &body.to_string(),
+            notify_cfg,
+            &runtime_cfg,
+            &client,
+            limiter.clone(),
+            digester.clone(),
+            None,
+        )
+        .await;
+    }
+}
+
+async fn fetch(cfg: &PollerConfig, client: &Client) -> Result<Value, String> {
+    let mut req = client
+        .get(&cfg.url)
+        .timeout(Duration::from_secs(cfg.timeout.unwrap_or(10)));
+    for (name, value) in cfg.headers.iter().flatten() {
+        req = req.header(name, value);
+    }
+
+    let res = req
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+    res.json().await.map_err(|e| e.to_string())
+}
+
+/// Names of the watched contents differing between the snapshots.
+fn changes(
+    cfg: &PollerConfig,
+    old: &HashMap<String, String>,
+    new: &HashMap<String, String>,
+) -> Vec<String> {
+    let names: Vec<&String> = match &cfg.fields {
+        Some(fields) => fields.iter().collect(),
+        None => cfg.extractors.iter().map(|e| &e.name).collect(),
+    };
+
+    names
+        .into_iter()
+        .filter(|name| old.get(*name) != new.get(*name))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::changes;
+    use crate::config::PollerConfig;
+
+    #[test]
+    fn test_changes() {
+        let mut cfg: PollerConfig = serde_json::from_value(json!({
+            "url": "http://localhost/status",
+            "interval": 60,
+            "extractors": [
+                { "name": "status", "path": "status" },
+                { "name": "version", "path": "version" },
+                { "name": "uptime", "path": "uptime" }
+            ],
+            "notification": "status"
+        }))
+        .unwrap();
+        let contents = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+
+        let old = contents(&[("status", "up"), ("version", "1.0"), ("uptime", "10")]);
+        let new = contents(&[("status", "down"), ("uptime", "20")]);
+        assert_eq!(
+            changes(&cfg, &old, &new),
+            vec!["status", "version", "uptime"]
+        );
+
+        cfg.fields = Some(vec!["status".to_string()]);
+        assert_eq!(changes(&cfg, &old, &new), vec!["status"]);
+        assert!(changes(&cfg, &old, &old).is_empty());
+    }
+}
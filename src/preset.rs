@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+use tklog::warn;
+
+use crate::{
+    config::{
+        AuthConfig, ContentExtractConfig, HmacAlgorithm, HmacConfig, NotifyConfig, PresetConfig,
+        RouteConfig, SignatureEncoding,
+    },
+    onebot::Message,
+    service::{extract_contents, format_message},
+};
+
+/// How a preset turns the payload into messages.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Renderer {
+    /// Renders a single message from the extracted contents.
+    Template,
+    /// Renders Alertmanager style grouped alerts, see [`alerts`].
+    Alerts,
+}
+
+/// A reusable set of defaults for notifications receiving webhooks of the same provider.
+#[derive(Clone)]
+pub struct Preset {
+    pub auth: AuthConfig,
+    pub renderer: Renderer,
+    pub extractors: Vec<ContentExtractConfig>,
+    pub message: String,
+    pub resolved_message: Option<String>,
+}
+
+/// Builds the preset registry from the built-in presets and the ones defined in the config.
+pub fn registry(custom: &[PresetConfig]) -> HashMap<String, Preset> {
+    let mut presets = builtin();
+
+    for p in custom {
+        if presets.contains_key(&p.name) {
+            warn!(format!("Preset [{}] is overridden by the config.", p.name));
+        }
+        presets.insert(
+            p.name.clone(),
+            Preset {
+                auth: p.auth.clone().unwrap_or(AuthConfig::Bearer),
+                renderer: Renderer::Template,
+                extractors: p.extractors.clone().unwrap_or_default(),
+                message: p.message.clone(),
+                resolved_message: None,
+            },
+        );
+    }
+
+    presets
+}
+
+fn builtin() -> HashMap<String, Preset> {
+    let mut presets = HashMap::new();
+
+    presets.insert(
+        "alertmanager".to_string(),
+        Preset {
+            auth: AuthConfig::Bearer,
+            renderer: Renderer::Alerts,
+            extractors: vec![
+                extractor("group_key", "groupKey", ""),
+                extractor("receiver", "receiver", ""),
+                extractor("external_url", "externalURL", ""),
+                extractor("alertnames", "alerts.[].labels.alertname", ", "),
+                extractor("instances", "alerts.[].labels.instance", ", "),
+                extractor("summaries", "alerts.[].annotations.summary", "\n"),
+            ],
+            message: "[FIRING:{count}] {alertnames}\n{summaries}\nGroup: {group_labels}"
+                .to_string(),
+            resolved_message: Some(
+                "[RESOLVED:{count}] {alertnames}\n{summaries}\nGroup: {group_labels}".to_string(),
+            ),
+        },
+    );
+
+    presets.insert(
+        "grafana".to_string(),
+        Preset {
+            auth: AuthConfig::Bearer,
+            renderer: Renderer::Alerts,
+            extractors: vec![
+                extractor("title", "title", ""),
+                extractor("group_key", "groupKey", ""),
+                extractor("receiver", "receiver", ""),
+                extractor("external_url", "externalURL", ""),
+                extractor("alertnames", "alerts.[].labels.alertname", ", "),
+                extractor("summaries", "alerts.[].annotations.summary", "\n"),
+                extractor("values", "alerts.[].valueString", "\n"),
+                extractor("panel_urls", "alerts.[].panelURL", "\n"),
+            ],
+            // The title of the payload covers every alert, including the ones split off by status.
+            message: "[FIRING:{count}] {alertnames}\n{summaries}".to_string(),
+            resolved_message: Some("[RESOLVED:{count}] {alertnames}\n{summaries}".to_string()),
+        },
+    );
+
+    presets.insert(
+        "uptime-kuma".to_string(),
+        Preset {
+            auth: AuthConfig::Bearer,
+            renderer: Renderer::Template,
+            extractors: vec![
+                extractor("msg", "msg", ""),
+                extractor("monitor", "monitor.name", ""),
+                extractor("url", "monitor.url", ""),
+                extractor("detail", "heartbeat.msg", ""),
+                extractor("time", "heartbeat.time", ""),
+            ],
+            message: "{msg}".to_string(),
+            resolved_message: None,
+        },
+    );
+
+    presets.insert(
+        "gitea".to_string(),
+        Preset {
+            // Forgejo sends the same signature in X-Gitea-Signature for compatibility.
+            auth: AuthConfig::Hmac(HmacConfig {
+                header: "X-Gitea-Signature".to_string(),
+                algorithm: Some(HmacAlgorithm::Sha256),
+                prefix: None,
+                encoding: Some(SignatureEncoding::Hex),
+                timestamp_header: None,
+                tolerance: None,
+                payload: None,
+            }),
+            renderer: Renderer::Template,
+            extractors: vec![
+                extractor("repository", "repository.full_name", ""),
+                extractor("sender", "sender.login", ""),
+                extractor("ref", "ref", ""),
+                extractor("compare_url", "compare_url", ""),
+                extractor("commits", "commits.[].message", "\n"),
+            ],
+            message: "[{repository}] {sender} pushed to {ref}\n{commits}\n{compare_url}"
+                .to_string(),
+            resolved_message: None,
+        },
+    );
+
+    presets
+}
+
+impl Preset {
+    /// Renders the request body with the defaults of the preset and the overrides of the notification.
+    pub fn render(&self, body: &str, cfg: &NotifyConfig) -> Vec<Message> {
+        let payload: Value = match serde_json::from_str(body) {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!(format!("Failed to parse body as json: {}", err));
+                return vec![];
+            }
+        };
+
+        let extractors = self.merge_extractors(cfg);
+        let message = cfg.message.as_deref().unwrap_or(&self.message);
+
+        match self.renderer {
+            Renderer::Template => {
+                let contents = extract_contents(&payload, &extractors);
+                vec![Message::new(format_message(message, &contents), cfg)]
+            }
+            Renderer::Alerts => {
+                let resolved_message = cfg
+                    .resolved_message
+                    .as_deref()
+                    .or(self.resolved_message.as_deref())
+                    .unwrap_or(message);
+                alerts(&payload, cfg, &extractors, message, resolved_message)
+            }
+        }
+    }
+
+    /// Extractors of the preset, overridden by the ones with the same name in the notification.
+    pub fn merge_extractors(&self, cfg: &NotifyConfig) -> Vec<ContentExtractConfig> {
+        let custom = cfg.extractors.clone().unwrap_or_default();
+        let mut extractors: Vec<ContentExtractConfig> = self
+            .extractors
+            .iter()
+            .filter(|d| !custom.iter().any(|c| c.name == d.name))
+            .cloned()
+            .collect();
+        extractors.extend(custom);
+        extractors
+    }
+}
+
+fn extractor(name: &str, path: &str, sep: &str) -> ContentExtractConfig {
+    ContentExtractConfig {
+        name: name.to_string(),
+        path: path.to_string(),
+        fallback: Some(String::new()),
+        sep: Some(sep.to_string()),
+        limit: None,
+    }
+}
+
+/// Renders Alertmanager style webhooks.
+///
+/// Alerts are dispatched to the first route whose label matches, or to the default targets of
+/// the notification otherwise. Each target set receives one message for the firing alerts and
+/// one for the resolved alerts of the group.
+///
+/// Alertmanager sends one webhook per group, so the payload is taken as a single group. Webhooks
+/// of the same `groupKey` are not merged.
+fn alerts(
+    payload: &Value,
+    cfg: &NotifyConfig,
+    extractors: &[ContentExtractConfig],
+    firing_message: &str,
+    resolved_message: &str,
+) -> Vec<Message> {
+    let alerts = match payload.get("alerts").and_then(Value::as_array) {
+        Some(alerts) => alerts,
+        None => {
+            warn!("No alerts found in the payload.");
+            return vec![];
+        }
+    };
+
+    let routes = cfg.routes.clone().unwrap_or_default();
+
+    // The last bucket holds the alerts that match no route.
+    let mut buckets: Vec<Vec<Value>> = vec![vec![]; routes.len() + 1];
+    for alert in alerts {
+        let idx = routes
+            .iter()
+            .position(|r| route_matches(r, alert))
+            .unwrap_or(routes.len());
+        buckets[idx].push(alert.clone());
+    }
+
+    let group_labels = payload
+        .get("groupLabels")
+        .and_then(Value::as_object)
+        .map(|labels| {
+            labels
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v.as_str().unwrap_or_default()))
+                .collect::<Vec<String>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+
+    let mut messages = vec![];
+    for (idx, bucket) in buckets.into_iter().enumerate() {
+        let targets = match routes.get(idx) {
+            Some(route) => Message {
+                text: String::new(),
+                groups: route.groups.clone().unwrap_or_default(),
+                users: route.users.clone().unwrap_or_default(),
+                mentions: route.mentions.clone().unwrap_or_default(),
+            },
+            None => Message::new(String::new(), cfg),
+        };
+
+        for status in ["firing", "resolved"] {
+            let selected: Vec<Value> = bucket
+                .iter()
+                .filter(|a| a.get("status").and_then(Value::as_str) == Some(status))
+                .cloned()
+                .collect();
+            if selected.is_empty() {
+                continue;
+            }
+
+            let template = if status == "firing" {
+                firing_message
+            } else {
+                resolved_message
+            };
+
+            let mut group = payload.clone();
+            group["status"] = Value::from(status);
+            group["alerts"] = Value::from(selected.clone());
+
+            let mut contents = extract_contents(&group, extractors);
+            contents.insert("status".to_string(), status.to_string());
+            contents.insert("count".to_string(), selected.len().to_string());
+            contents.insert("group_labels".to_string(), group_labels.clone());
+
+            messages.push(Message {
+                text: format_message(template, &contents),
+                ..targets.clone()
+            });
+        }
+    }
+
+    messages
+}
+
+fn route_matches(route: &RouteConfig, alert: &Value) -> bool {
+    alert
+        .get("labels")
+        .and_then(|labels| labels.get(&route.label))
+        .and_then(Value::as_str)
+        == Some(route.value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::registry;
+    use crate::{
+        auth::authorize,
+        config::{NotifyConfig, PresetConfig},
+    };
+    use actix_web::test::TestRequest;
+    use futures::executor::block_on;
+    use hmac::{Hmac, Mac};
+    use serde_json::json;
+    use sha2::Sha256;
+
+    #[test]
+    fn test_alertmanager() {
+        let cfg: NotifyConfig = serde_json::from_value(json!({
+            "id": "alerts",
+            "groups": ["1"],
+            "preset": "alertmanager",
+            "resolved_message": "OK {alertnames}",
+            "routes": [{ "label": "team", "value": "db", "groups": ["2"] }]
+        }))
+        .unwrap();
+
+        let body = json!({
+            "status": "firing",
+            "groupKey": "{}:{alertname=\"HighLoad\"}",
+            "groupLabels": { "alertname": "HighLoad" },
+            "alerts": [
+                {
+                    "status": "firing",
+                    "labels": { "alertname": "HighLoad", "team": "web" },
+                    "annotations": { "summary": "web is busy" }
+                },
+                {
+                    "status": "resolved",
+                    "labels": { "alertname": "HighLoad", "team": "web" },
+                    "annotations": { "summary": "web is fine" }
+                },
+                {
+                    "status": "firing",
+                    "labels": { "alertname": "HighLoad", "team": "db" },
+                    "annotations": { "summary": "db is busy" }
+                }
+            ]
+        });
+
+        let presets = registry(&[]);
+        let messages = presets["alertmanager"].render(&body.to_string(), &cfg);
+        assert_eq!(messages.len(), 3);
+
+        assert_eq!(messages[0].groups, vec!["2"]);
+        assert_eq!(
+            messages[0].text,
+            "[FIRING:1] HighLoad\ndb is busy\nGroup: alertname=HighLoad"
+        );
+
+        assert_eq!(messages[1].groups, vec!["1"]);
+        assert_eq!(
+            messages[1].text,
+            "[FIRING:1] HighLoad\nweb is busy\nGroup: alertname=HighLoad"
+        );
+
+        assert_eq!(messages[2].groups, vec!["1"]);
+        assert_eq!(messages[2].text, "OK HighLoad");
+    }
+
+    #[test]
+    fn test_grafana() {
+        let cfg: NotifyConfig = serde_json::from_value(json!({
+            "id": "grafana",
+            "groups": ["1"],
+            "preset": "grafana"
+        }))
+        .unwrap();
+
+        let body = json!({
+            "receiver": "notify-bot",
+            "status": "firing",
+            "orgId": 1,
+            "alerts": [
+                {
+                    "status": "firing",
+                    "labels": { "alertname": "DiskFull", "instance": "db-1" },
+                    "annotations": { "summary": "Disk of db-1 is 95% full" },
+                    "startsAt": "2024-05-01T10:00:00Z",
+                    "endsAt": "0001-01-01T00:00:00Z",
+                    "generatorURL": "https://grafana.example.com/alerting/grafana/abc/view",
+                    "fingerprint": "8c1d7a3e5f0b2c4d",
+                    "silenceURL": "https://grafana.example.com/alerting/silence/new",
+                    "dashboardURL": "https://grafana.example.com/d/disk",
+                    "panelURL": "https://grafana.example.com/d/disk?viewPanel=2",
+                    "values": { "B": 95.0 },
+                    "valueString": "[ var='B' labels={instance=db-1} value=95 ]"
+                },
+                {
+                    "status": "resolved",
+                    "labels": { "alertname": "DiskFull", "instance": "db-2" },
+                    "annotations": { "summary": "Disk of db-2 is 60% full" },
+                    "startsAt": "2024-05-01T09:00:00Z",
+                    "endsAt": "2024-05-01T10:00:00Z",
+                    "generatorURL": "https://grafana.example.com/alerting/grafana/abc/view",
+                    "fingerprint": "4b2a9c7d1e3f5a6b",
+                    "panelURL": "https://grafana.example.com/d/disk?viewPanel=2",
+                    "values": { "B": 60.0 },
+                    "valueString": "[ var='B' labels={instance=db-2} value=60 ]"
+                }
+            ],
+            "groupLabels": { "alertname": "DiskFull" },
+            "commonLabels": { "alertname": "DiskFull" },
+            "commonAnnotations": {},
+            "externalURL": "https://grafana.example.com/",
+            "version": "1",
+            "groupKey": "{}:{alertname=\"DiskFull\"}",
+            "truncatedAlerts": 0,
+            "title": "[FIRING:1, RESOLVED:1] DiskFull",
+            "state": "alerting",
+            "message": "**Firing**\n\nValue: B=95"
+        });
+
+        let messages = registry(&[])["grafana"].render(&body.to_string(), &cfg);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0].text,
+            "[FIRING:1] DiskFull\nDisk of db-1 is 95% full"
+        );
+        assert_eq!(
+            messages[1].text,
+            "[RESOLVED:1] DiskFull\nDisk of db-2 is 60% full"
+        );
+    }
+
+    #[test]
+    fn test_uptime_kuma() {
+        let cfg: NotifyConfig = serde_json::from_value(json!({
+            "id": "uptime",
+            "groups": ["1"],
+            "preset": "uptime-kuma",
+            "message": "{monitor} ({url}): {detail}"
+        }))
+        .unwrap();
+
+        let body = json!({
+            "heartbeat": {
+                "monitorID": 3,
+                "status": 0,
+                "time": "2024-05-01 10:00:00.123",
+                "msg": "Request failed with status code 502",
+                "important": true,
+                "duration": 60,
+                "timezone": "Asia/Shanghai",
+                "timezoneOffset": "+08:00",
+                "localDateTime": "2024-05-01 18:00:00"
+            },
+            "monitor": {
+                "id": 3,
+                "name": "Homepage",
+                "url": "https://example.com",
+                "type": "http",
+                "interval": 60,
+                "active": true
+            },
+            "msg": "[Homepage] [🔴 Down] Request failed with status code 502"
+        });
+
+        let presets = registry(&[]);
+        let messages = presets["uptime-kuma"].render(&body.to_string(), &cfg);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].text,
+            "Homepage (https://example.com): Request failed with status code 502"
+        );
+
+        let messages = presets["uptime-kuma"].render(
+            &body.to_string(),
+            &NotifyConfig {
+                message: None,
+                ..cfg
+            },
+        );
+        assert_eq!(
+            messages[0].text,
+            "[Homepage] [🔴 Down] Request failed with status code 502"
+        );
+    }
+
+    #[test]
+    fn test_gitea() {
+        let cfg: NotifyConfig = serde_json::from_value(json!({
+            "id": "gitea",
+            "groups": ["1"],
+            "preset": "gitea",
+            "token": "gitea-secret"
+        }))
+        .unwrap();
+
+        let body = json!({
+            "ref": "refs/heads/main",
+            "before": "28e1879d029cb852e4844d9c718537df08844e03",
+            "after": "bffeb74224043ba2feb48d137756c8a9331c449a",
+            "compare_url": "https://gitea.example.com/octocat/hello/compare/28e1879d029c...bffeb7422404",
+            "commits": [
+                {
+                    "id": "a1b2c3d4e5f60718293a4b5c6d7e8f9012345678",
+                    "message": "Fix typo in README\n",
+                    "url": "https://gitea.example.com/octocat/hello/commit/a1b2c3d4e5f6",
+                    "author": { "name": "Octocat", "email": "octocat@example.com", "username": "octocat" },
+                    "timestamp": "2024-05-01T10:00:00+08:00"
+                },
+                {
+                    "id": "bffeb74224043ba2feb48d137756c8a9331c449a",
+                    "message": "Bump version\n",
+                    "url": "https://gitea.example.com/octocat/hello/commit/bffeb7422404",
+                    "author": { "name": "Octocat", "email": "octocat@example.com", "username": "octocat" },
+                    "timestamp": "2024-05-01T10:05:00+08:00"
+                }
+            ],
+            "total_commits": 2,
+            "head_commit": {
+                "id": "bffeb74224043ba2feb48d137756c8a9331c449a",
+                "message": "Bump version\n"
+            },
+            "repository": {
+                "id": 1,
+                "name": "hello",
+                "full_name": "octocat/hello",
+                "html_url": "https://gitea.example.com/octocat/hello",
+                "default_branch": "main"
+            },
+            "pusher": { "id": 1, "login": "octocat", "username": "octocat" },
+            "sender": { "id": 1, "login": "octocat", "username": "octocat" }
+        })
+        .to_string();
+
+        let presets = registry(&[]);
+        let preset = &presets["gitea"];
+        let messages = preset.render(&body, &cfg);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].text,
+            "[octocat/hello] octocat pushed to refs/heads/main\nFix typo in README\n\nBump version\n\nhttps://gitea.example.com/octocat/hello/compare/28e1879d029c...bffeb7422404"
+        );
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"gitea-secret").unwrap();
+        mac.update(body.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+        let check = |signature: &str| {
+            let req = TestRequest::default()
+                .insert_header(("X-Gitea-Signature", signature))
+                .to_http_request();
+            block_on(authorize(&preset.auth, &cfg.all_tokens(), &req, &body))
+        };
+        assert!(check(&signature).is_ok());
+        assert!(check(&"0".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_custom_preset() {
+        let presets = registry(&[serde_json::from_value::<PresetConfig>(json!({
+            "name": "drone",
+            "message": "{repo}: {status}",
+            "extractors": [
+                { "name": "repo", "path": "repo.slug" },
+                { "name": "status", "path": "build.status" }
+            ]
+        }))
+        .unwrap()]);
+
+        let cfg: NotifyConfig = serde_json::from_value(json!({
+            "id": "ci",
+            "groups": ["1"],
+            "preset": "drone",
+            "extractors": [{ "name": "status", "path": "build.event" }]
+        }))
+        .unwrap();
+
+        let body = json!({
+            "repo": { "slug": "octocat/hello" },
+            "build": { "status": "success", "event": "push" }
+        });
+
+        let messages = presets["drone"].render(&body.to_string(), &cfg);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].text, "octocat/hello: push");
+        assert!(presets.contains_key("gitea"));
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use tklog::info;
+
+use crate::{
+    config::{QuietConfig, QuietRange},
+    onebot::Message,
+    service::Sender,
+};
+
+/// The end of the range if the local time is within it.
+fn range_end(range: &QuietRange, time: NaiveDateTime) -> Option<NaiveDateTime> {
+    let starts_on = |date: NaiveDateTime| {
+        range
+            .days
+            .as_ref()
+            .is_none_or(|days| days.contains(&date.weekday()))
+    };
+    let day = TimeDelta::days(1);
+
+    if range.from < range.to {
+        (starts_on(time) && range.from <= time.time() && time.time() < range.to)
+            .then(|| time.date().and_time(range.to))
+    } else if time.time() >= range.from {
+        // Ends the day after, or lasts a whole day if the bounds are equal
+        starts_on(time).then(|| (time + day).date().and_time(range.to))
+    } else if time.time() < range.to {
+        starts_on(time - day).then(|| time.date().and_time(range.to))
+    } else {
+        None
+    }
+}
+
+/// The end of the quiet hours if they're in effect, following adjacent ranges.
+pub fn quiet_until(cfg: &QuietConfig, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let tz = cfg.timezone.unwrap_or(Tz::UTC);
+    let mut time = now.with_timezone(&tz).naive_local();
+    let mut quiet = false;
+
+    // Bounded, since ranges covering every day would never end
+    for _ in 0..=cfg.ranges.len() * 7 {
+        match cfg.ranges.iter().find_map(|range| range_end(range, time)) {
+            Some(end) => {
+                time = end;
+                quiet = true;
+            }
+            None => break,
+        }
+    }
+
+    quiet.then(|| {
+        tz.from_local_datetime(&time)
+            .earliest()
+            .map_or_else(|| tz.from_utc_datetime(&time).to_utc(), |t| t.to_utc())
+    })
+}
+
+/// The messages addressed to the targets of the quiet hours instead.
+pub fn redirect(cfg: &QuietConfig, messages: Vec<Message>) -> Vec<Message> {
+    messages
+        .into_iter()
+        .map(|msg| Message {
+            groups: cfg.groups.clone().unwrap_or_default(),
+            users: cfg.users.clone().unwrap_or_default(),
+            mentions: cfg.mentions.clone().unwrap_or_default(),
+            ..msg
+        })
+        .collect()
+}
+
+/// Delivers the messages once the quiet hours end, buffering them for the digest or sending them
+/// within the limits as usual.
+pub fn hold(messages: Vec<Message>, until: DateTime<Utc>, sender: Sender) {
+    let wait = (until - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+    tokio::spawn(async move {
+        tokio::time::sleep(wait).await;
+
+        info!("Quiet hours ended. Delivering the held messages.");
+        sender.deliver(messages);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use serde_json::json;
+
+    use super::quiet_until;
+    use crate::config::QuietConfig;
+
+    #[test]
+    fn test_quiet_hours() {
+        let cfg: QuietConfig = serde_json::from_value(json!({
+            "timezone": "Asia/Shanghai",
+            "ranges": [
+                { "days": ["fri", "sat"], "from": "22:00", "to": "00:00" },
+                { "from": "00:00", "to": "08:00" },
+                { "days": ["sun"], "from": "08:00", "to": "08:00" }
+            ]
+        }))
+        .unwrap();
+        let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+        let until = |s: &str| quiet_until(&cfg, at(s));
+
+        // Friday 21:00 and 23:00 in Shanghai, the latter lasting until Saturday 08:00
+        assert_eq!(until("2024-06-07T13:00:00Z"), None);
+        assert_eq!(
+            until("2024-06-07T15:00:00Z"),
+            Some(at("2024-06-08T00:00:00Z"))
+        );
+        // Thursday 23:00
+        assert_eq!(until("2024-06-06T15:00:00Z"), None);
+        // Tuesday 07:00
+        assert_eq!(
+            until("2024-06-10T23:00:00Z"),
+            Some(at("2024-06-11T00:00:00Z"))
+        );
+        // Saturday 23:00 and Sunday 13:00, lasting until Monday 08:00
+        assert_eq!(
+            until("2024-06-08T15:00:00Z"),
+            Some(at("2024-06-10T00:00:00Z"))
+        );
+        assert_eq!(
+            until("2024-06-09T05:00:00Z"),
+            Some(at("2024-06-10T00:00:00Z"))
+        );
+    }
+}
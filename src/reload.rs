@@ -0,0 +1,375 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use reqwest::Client;
+use serde_json::{Map, Value};
+use tklog::{error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::{
+    check::{self, Report},
+    config::{Config, Format, Overrides, RuntimeConfig},
+    digest::Digester,
+    limit::RateLimiter,
+    poll, preset, schedule,
+};
+
+/// Sections of the config only applied on startup.
+const RESTART_REQUIRED: [&str; 2] = ["log", "watch"];
+/// Fields of the server section only applied on startup.
+const SERVER_RESTART_REQUIRED: [&str; 3] = ["ip", "port", "tls"];
+/// Fields of the admin section only applied on startup.
+const ADMIN_RESTART_REQUIRED: [&str; 2] = ["ip", "port"];
+
+/// Reads and checks the config file, along with its resolved raw value telling what changed on reload.
+///
+/// The report only holds warnings if the config is valid.
+pub fn read(path: &Path, overrides: &Overrides) -> Result<(Config, Value, Report), Report> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            return Err(Report {
+                errors: vec![format!("Failed to read config file: {}", e)],
+                warnings: vec![],
+            })
+        }
+    };
+
+    let format = Format::of(path);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    match check::check(&content, format, dir, overrides) {
+        (Some((conf, raw)), report) if report.is_ok() => Ok((conf, raw, report)),
+        (_, report) => Err(report),
+    }
+}
+
+pub fn build(conf: &Config) -> RuntimeConfig {
+    RuntimeConfig {
+        server: conf.server.clone(),
+        onebot: conf.onebot.clone(),
+        notifications: conf
+            .notifications
+            .iter()
+            .map(|n| (n.id.clone(), n.clone()))
+            .collect(),
+        presets: preset::registry(conf.presets.as_deref().unwrap_or_default()),
+        schedules: conf.schedules.clone().unwrap_or_default(),
+        pollers: conf.pollers.clone().unwrap_or_default(),
+        dry_run: conf.dry_run.unwrap_or(false),
+        admin: conf.admin.clone(),
+    }
+}
+
+/// The differences between the raw configs, such as `notification [id] added` or `server.allow changed`.
+///
+/// The server and the admin sections are compared field by field.
+fn changes(old: &Value, new: &Value) -> Vec<String> {
+    let empty = Map::new();
+    let sections = |v: &Value| v.as_object().cloned().unwrap_or_default();
+    let (old_sections, new_sections) = (sections(old), sections(new));
+    let mut changes = vec![];
+
+    let notifications = |v: &Value| -> Vec<(String, Value)> {
+        v.get("notifications")
+            .and_then(|n| n.as_array())
+            .into_iter()
+            .flatten()
+            .map(|n| {
+                let id = n.get("id").and_then(|id| id.as_str()).unwrap_or_default();
+                (id.to_string(), n.clone())
+            })
+            .collect()
+    };
+    let (old_notifications, new_notifications) = (notifications(old), notifications(new));
+    for (id, n) in &new_notifications {
+        match old_notifications.iter().find(|(old_id, _)| old_id == id) {
+            None => changes.push(format!("notification [{}] added", id)),
+            Some((_, old_n)) if old_n != n => {
+                changes.push(format!("notification [{}] changed", id))
+            }
+            _ => {}
+        }
+    }
+    for (id, _) in &old_notifications {
+        if !new_notifications.iter().any(|(new_id, _)| new_id == id) {
+            changes.push(format!("notification [{}] removed", id));
+        }
+    }
+
+    let mut keys: Vec<&String> = old_sections.keys().chain(new_sections.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        if key == "notifications" || old_sections.get(key) == new_sections.get(key) {
+            continue;
+        }
+        if key == "server" || key == "admin" {
+            let fields =
+                |v: Option<&Value>| v.and_then(|v| v.as_object()).unwrap_or(&empty).clone();
+            let (old_fields, new_fields) =
+                (fields(old_sections.get(key)), fields(new_sections.get(key)));
+            let mut fields: Vec<&String> = old_fields.keys().chain(new_fields.keys()).collect();
+            fields.sort();
+            fields.dedup();
+            for field in fields {
+                if old_fields.get(field) != new_fields.get(field) {
+                    changes.push(format!("{}.{} changed", key, field));
+                }
+            }
+        } else {
+            changes.push(format!("{} changed", key));
+        }
+    }
+
+    changes
+}
+
+fn requires_restart(change: &str) -> bool {
+    RESTART_REQUIRED
+        .iter()
+        .any(|section| change == format!("{} changed", section))
+        || SERVER_RESTART_REQUIRED
+            .iter()
+            .any(|field| change == format!("server.{} changed", field))
+        || ADMIN_RESTART_REQUIRED
+            .iter()
+            .any(|field| change == format!("admin.{} changed", field))
+}
+
+/// Holds the config swapped on reload, and the tasks of its schedules and pollers.
+pub struct Reloader {
+    path: PathBuf,
+    overrides: Overrides,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+    raw: Mutex<Value>,
+    // Held across reading, comparing and swapping, so that an older read never replaces a newer one
+    reloading: Mutex<()>,
+    client: Client,
+    limiter: Data<RateLimiter>,
+    digester: Data<Digester>,
+    schedules: Mutex<Vec<JoinHandle<()>>>,
+    pollers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Reloader {
+    /// Starts the schedules and the pollers of the config.
+    pub fn new(
+        path: &Path,
+        overrides: Overrides,
+        conf: Data<ArcSwap<RuntimeConfig>>,
+        raw: Value,
+        client: Client,
+        limiter: Data<RateLimiter>,
+        digester: Data<Digester>,
+    ) -> Reloader {
+        let reloader = Reloader {
+            path: path.to_path_buf(),
+            overrides,
+            conf,
+            raw: Mutex::new(raw),
+            reloading: Mutex::new(()),
+            client,
+            limiter,
+            digester,
+            schedules: Mutex::new(vec![]),
+            pollers: Mutex::new(vec![]),
+        };
+        reloader.restart_schedules();
+        reloader.restart_pollers();
+        reloader
+    }
+
+    fn restart_schedules(&self) {
+        let mut tasks = self.schedules.lock().unwrap();
+        tasks.drain(..).for_each(|task| task.abort());
+        *tasks = schedule::start(
+            self.conf.clone(),
+            &self.client,
+            self.limiter.clone(),
+            self.digester.clone(),
+        );
+    }
+
+    fn restart_pollers(&self) {
+        let mut tasks = self.pollers.lock().unwrap();
+        tasks.drain(..).for_each(|task| task.abort());
+        *tasks = poll::start(
+            self.conf.clone(),
+            &self.client,
+            self.limiter.clone(),
+            self.digester.clone(),
+        );
+    }
+
+    /// Reads the config file again, swapping the config only if it's valid.
+    ///
+    /// Returns the warnings of the config, or the errors keeping the current one.
+    pub fn reload(&self) -> Result<Report, Report> {
+        let _guard = self.reloading.lock().unwrap();
+        info!("Reloading Config...");
+
+        let (conf, raw, report) = match read(&self.path, &self.overrides) {
+            Ok(res) => res,
+            Err(report) => {
+                report.log();
+                error!("Invalid config. Keeping the current one.");
+                return Err(report);
+            }
+        };
+
+        let mut current = self.raw.lock().unwrap();
+        let changes = changes(&current, &raw);
+        if changes.is_empty() {
+            info!("Config Unchanged");
+            return Ok(report);
+        }
+
+        report.log();
+        self.conf.store(Arc::new(build(&conf)));
+        *current = raw;
+        drop(current);
+
+        for change in &changes {
+            if requires_restart(change) {
+                warn!(format!(
+                    "Config: {}, which takes effect after restart.",
+                    change
+                ));
+            } else {
+                info!(format!("Config: {}", change));
+            }
+        }
+        if changes.iter().any(|c| c == "schedules changed") {
+            self.restart_schedules();
+        }
+        if changes.iter().any(|c| c == "pollers changed") {
+            self.restart_pollers();
+        }
+        info!("Config Reloaded");
+        Ok(report)
+    }
+
+    /// The directory of the config file, which the files it refers to are relative to.
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("."))
+    }
+
+    /// The raw value of the current config.
+    pub fn raw(&self) -> Value {
+        self.raw.lock().unwrap().clone()
+    }
+
+    /// The config file, the files it includes and the admin store.
+    fn files(&self) -> Vec<PathBuf> {
+        let dir = self.dir();
+        let raw = self.raw.lock().unwrap();
+        let patterns = raw.get("include").and_then(|i| i.as_array()).cloned();
+
+        let mut files = vec![self.path.clone()];
+        for pattern in patterns.iter().flatten().filter_map(|p| p.as_str()) {
+            files.extend(check::included(dir, pattern).unwrap_or_default());
+        }
+        if let Some(admin) = &self.conf.load().admin {
+            files.push(admin.store(dir));
+        }
+        files
+    }
+
+    /// Reloads the config on SIGHUP, and whenever any of the files is modified if `watch` is set.
+    pub fn watch(self: Arc<Self>, file: bool) {
+        #[cfg(unix)]
+        let reloader = self.clone();
+        #[cfg(unix)]
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    warn!(format!("Failed to listen for SIGHUP: {}", e));
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                let _ = reloader.reload();
+            }
+        });
+
+        if !file {
+            return;
+        }
+        tokio::spawn(async move {
+            let modified = |files: Vec<PathBuf>| -> Vec<(PathBuf, Option<SystemTime>)> {
+                files
+                    .into_iter()
+                    .map(|f| {
+                        let time = fs::metadata(&f).and_then(|m| m.modified()).ok();
+                        (f, time)
+                    })
+                    .collect()
+            };
+            let mut last = modified(self.files());
+            let mut interval = tokio::time::interval(Duration::from_secs(2));
+
+            loop {
+                interval.tick().await;
+                let current = modified(self.files());
+                if current != last {
+                    last = current;
+                    let _ = self.reload();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{changes, requires_restart};
+
+    #[test]
+    fn test_changes() {
+        let old = json!({
+            "server": { "ip": "0.0.0.0", "port": 10000 },
+            "onebot": { "url": "http://localhost:3000" },
+            "notifications": [
+                { "id": "a", "message": "a" },
+                { "id": "b", "message": "b" }
+            ]
+        });
+        let new = json!({
+            "server": { "ip": "0.0.0.0", "port": 10001, "allow": ["10.0.0.0/8"] },
+            "onebot": { "url": "http://localhost:3000" },
+            "notifications": [
+                { "id": "b", "message": "B" },
+                { "id": "c", "message": "c" }
+            ],
+            "schedules": []
+        });
+
+        assert_eq!(
+            changes(&old, &new),
+            vec![
+                "notification [b] changed",
+                "notification [c] added",
+                "notification [a] removed",
+                "schedules changed",
+                "server.allow changed",
+                "server.port changed",
+            ]
+        );
+        assert!(changes(&new, &new).is_empty());
+        assert!(requires_restart("server.port changed"));
+        assert!(!requires_restart("server.allow changed"));
+        assert!(requires_restart("admin.port changed"));
+        assert!(!requires_restart("admin.token changed"));
+    }
+}
@@ -0,0 +1,136 @@
+use std::str::FromStr;
+
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use chrono::Utc;
+use chrono_tz::Tz;
+use cron::Schedule;
+use reqwest::Client;
+use serde_json::json;
+use tklog::{error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::{
+    config::{RuntimeConfig, ScheduleConfig},
+    digest::Digester,
+    limit::RateLimiter,
+    service,
+};
+
+/// Parses a cron expression, accepting the standard one without seconds as well.
+pub fn parse(expr: &str) -> Result<Schedule, String> {
+    let expr = if expr.split_whitespace().count() == 5 {
+        format!("0 {}", expr)
+    } else {
+        expr.to_string()
+    };
+    Schedule::from_str(&expr).map_err(|e| format!("Invalid cron expression [{}]: {}", expr, e))
+}
+
+/// Spawns a task for every schedule of the config, sending its notification on time.
+pub fn start(
+    conf: Data<ArcSwap<RuntimeConfig>>,
+    client: &Client,
+    limiter: Data<RateLimiter>,
+    digester: Data<Digester>,
+) -> Vec<JoinHandle<()>> {
+    let runtime_cfg = conf.load();
+    let mut tasks = vec![];
+
+    for cfg in &runtime_cfg.schedules {
+        let schedule = match parse(&cfg.cron) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                error!(e);
+                continue;
+            }
+        };
+        if !runtime_cfg.notifications.contains_key(&cfg.notification) {
+            warn!(format!(
+                "Schedule [{}] sends an unknown notification [{}]. It won't take any effect.",
+                cfg.cron, cfg.notification
+            ));
+            continue;
+        }
+
+        tasks.push(tokio::spawn(run(
+            schedule,
+            cfg.clone(),
+            conf.clone(),
+            client.clone(),
+            limiter.clone(),
+            digester.clone(),
+        )));
+    }
+
+    tasks
+}
+
+async fn run(
+    schedule: Schedule,
+    cfg: ScheduleConfig,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+    client: Client,
+    limiter: Data<RateLimiter>,
+    digester: Data<Digester>,
+) {
+    let body = cfg.body.clone().unwrap_or_else(|| json!({})).to_string();
+
+    for next in schedule.upcoming_owned(cfg.timezone.unwrap_or(Tz::UTC)) {
+        let wait = (next.to_utc() - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        let runtime_cfg = conf.load_full();
+        let Some(notify_cfg) = runtime_cfg.notifications.get(&cfg.notification) else {
+            warn!(format!(
+                "Schedule [{}] sends an unknown notification [{}]. Skipped.",
+                cfg.cron, cfg.notification
+            ));
+            continue;
+        };
+        if !notify_cfg.is_enabled() {
+            info!(format!(
+                "Schedule [{}] sends a disabled notification [{}]. Skipped.",
+                cfg.cron, cfg.notification
+            ));
+            continue;
+        }
+        info!(format!(
+            "Schedule [{}] -> /notify-{}",
+            cfg.cron, cfg.notification
+        ));
+        service::handle_notify_request(
+            &body,
+            notify_cfg,
+            &runtime_cfg,
+            &client,
+            limiter.clone(),
+            digester.clone(),
+            None,
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::parse;
+
+    #[test]
+    fn test_parse() {
+        let after = "2024-06-07T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let next = |expr: &str| parse(expr).unwrap().after(&after).next().unwrap();
+
+        assert_eq!(
+            next("0 9 * * Mon-Fri").to_rfc3339(),
+            "2024-06-10T09:00:00+00:00"
+        );
+        assert_eq!(
+            next("30 0 13 * * *").to_rfc3339(),
+            "2024-06-07T13:00:30+00:00"
+        );
+        assert!(parse("0 9 * *").is_err());
+    }
+}
@@ -1,249 +1,812 @@
-use std::{collections::HashMap, vec};
+use std::{collections::HashMap, fmt, net::IpAddr, vec};
 
 use actix_web::{
+    http::StatusCode,
     post,
     web::{Data, Path},
     HttpRequest, HttpResponse, Responder,
 };
-use actix_web_httpauth::extractors::bearer::BearerAuth;
+use arc_swap::ArcSwap;
+use chrono::Utc;
 use reqwest::Client;
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use strfmt::strfmt;
 use tklog::{info, warn};
 
-use crate::config::{NotifyConfig, RuntimeConfig};
+use crate::{
+    access, auth,
+    config::{
+        ContentExtractConfig, DedupConfig, DigestConfig, NotifyConfig, OneBotConfig, QuietAction,
+        RateLimitAction, RateLimitConfig, RuntimeConfig,
+    },
+    dedup::Deduplicator,
+    digest::Digester,
+    limit::RateLimiter,
+    onebot::{self, Message},
+    quiet,
+};
 
+// Every piece of shared state is its own extractor
+#[allow(clippy::too_many_arguments)]
 #[post("/notify-{id}")]
 pub async fn notify(
     req: HttpRequest,
-    auth: Option<BearerAuth>,
     id: Path<String>,
     body: String,
-    conf: Data<RuntimeConfig>,
+    conf: Data<ArcSwap<RuntimeConfig>>,
     client: Data<Client>,
+    limiter: Data<RateLimiter>,
+    dedup: Data<Deduplicator>,
+    digester: Data<Digester>,
 ) -> impl Responder {
-    let id = id.into_inner();
+    let conf = conf.load_full();
+    let (cfg, client_ip) = match admit(&req, &conf, &format!("/notify-{}", id), &id, &body).await {
+        Ok(res) => res,
+        Err((status, reason)) => return HttpResponse::build(status).body(reason),
+    };
+    if !cfg.is_enabled() {
+        warn!(format!("Notification [{}] is disabled. Rejected.", id));
+        return HttpResponse::Forbidden().body("The notification is disabled.");
+    }
 
-    if let Some(addr) = req.peer_addr() {
-        info!(format!("{} -> /notify-{}", addr, id));
-    } else {
-        info!(format!("Unknown Addr -> /notify-{}", id));
+    // Dry runs must not be mistaken for duplicates of the real deliveries
+    let dedup_key = cfg
+        .dedup
+        .as_ref()
+        .filter(|_| !conf.dry_run(cfg))
+        .map(|dedup_cfg| (dedup_cfg, event_key(&req, &body, dedup_cfg, cfg, &conf)));
+    // Reserved before handling, so that the same event arriving meanwhile is a duplicate as well
+    if let Some((dedup_cfg, key)) = &dedup_key {
+        if !dedup.reserve(&cfg.id, dedup_cfg, key) {
+            info!(format!("Duplicate event [{}]. Dropped.", key));
+            return HttpResponse::Ok().finish();
+        }
     }
 
-    if let Some(cfg) = conf.notifications.get(&id) {
-        if cfg.token.is_none() || cfg.token == auth.as_ref().map(|a| a.token().to_string()) {
-            info!("Handling request body: \n", body);
-            handle_notify_request(&body, cfg, &conf, &client).await;
+    info!("Handling request body: \n", body);
+    let (res, delivered) =
+        handle_notify_request(&body, cfg, &conf, &client, limiter, digester, client_ip).await;
+
+    // Events rejected before anything is sent are expected to be retried, which must not be
+    // mistaken for duplicates.
+    if let Some((dedup_cfg, key)) = dedup_key {
+        if delivered {
+            dedup.remember(&cfg.id, dedup_cfg, &key);
         } else {
-            if auth.is_none() {
-                warn!("No token provided. Rejected.")
-            } else {
-                warn!(format!("Wrone token provided. Rejected."));
-            }
-            return HttpResponse::Unauthorized().body("Permission Denied.");
+            dedup.release(&cfg.id, &key);
         }
-    } else {
+    }
+    res
+}
+
+/// Renders the notification from a sample body without sending anything, returning the extracted
+/// contents, the problems of the extractors and the messages with their payloads.
+#[post("/preview-{id}")]
+pub async fn preview(
+    req: HttpRequest,
+    id: Path<String>,
+    body: String,
+    conf: Data<ArcSwap<RuntimeConfig>>,
+) -> impl Responder {
+    let conf = conf.load_full();
+    let (cfg, _) = match admit(&req, &conf, &format!("/preview-{}", id), &id, &body).await {
+        Ok(res) => res,
+        Err((status, reason)) => return HttpResponse::build(status).body(reason),
+    };
+
+    let (contents, warnings) = match serde_json::from_str::<Value>(&body) {
+        Ok(val) => extract_with_warnings(&val, &extractors_of(cfg, &conf)),
+        Err(err) => (
+            HashMap::new(),
+            vec![format!("Failed to parse body as json: {}", err)],
+        ),
+    };
+    let messages: Vec<Value> = render_messages(&body, cfg, &conf)
+        .iter()
+        .map(|msg| {
+            json!({
+                "text": msg.text,
+                "targets": msg.targets().iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+                "payloads": payloads_of(std::slice::from_ref(msg)),
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(json!({
+        "variables": contents,
+        "warnings": warnings,
+        "messages": messages,
+    }))
+}
+
+/// Checks the client may trigger the notification, returning its config and the client address,
+/// or the status and the reason rejecting it.
+async fn admit<'a>(
+    req: &HttpRequest,
+    conf: &'a RuntimeConfig,
+    endpoint: &str,
+    id: &str,
+    body: &str,
+) -> Result<(&'a NotifyConfig, Option<IpAddr>), (StatusCode, &'static str)> {
+    let client_ip = access::client_ip(
+        req,
+        conf.server.trusted_proxies.as_deref().unwrap_or_default(),
+    );
+    let addr = client_ip.map_or("Unknown Addr".to_string(), |ip| ip.to_string());
+    match req.peer_addr() {
+        Some(peer) if client_ip != Some(peer.ip().to_canonical()) => {
+            info!(format!("{} (via {}) -> {}", addr, peer, endpoint));
+        }
+        _ => info!(format!("{} -> {}", addr, endpoint)),
+    }
+
+    if !access::is_allowed(
+        client_ip,
+        conf.server.allow.as_deref(),
+        conf.server.deny.as_deref(),
+    ) {
+        warn!(format!(
+            "{} is not allowed to access the server. Rejected.",
+            addr
+        ));
+        return Err((StatusCode::FORBIDDEN, "Access Denied."));
+    }
+
+    let Some(cfg) = conf.notifications.get(id) else {
         warn!(format!(
             "No config found with the corresponding ID [{}]",
             id
         ));
-        return HttpResponse::NotFound().body("The requested notify ID is not registered.");
+        return Err((
+            StatusCode::NOT_FOUND,
+            "The requested notify ID is not registered.",
+        ));
+    };
+
+    if !access::is_allowed(client_ip, cfg.allow.as_deref(), cfg.deny.as_deref()) {
+        warn!(format!(
+            "{} is not allowed to trigger the notification. Rejected.",
+            addr
+        ));
+        return Err((StatusCode::FORBIDDEN, "Access Denied."));
     }
-    HttpResponse::Ok().finish()
+
+    let method = conf.auth_method(cfg);
+
+    match auth::authorize(&method, &cfg.all_tokens(), req, body).await {
+        Ok(label) => {
+            if let Some(label) = label {
+                info!(format!("Authenticated by token [{}]", label));
+            }
+        }
+        Err(reason) => {
+            warn!(format!("{} Rejected.", reason));
+            return Err((StatusCode::UNAUTHORIZED, "Permission Denied."));
+        }
+    }
+
+    Ok((cfg, client_ip))
 }
 
-async fn handle_notify_request(
+/// Identifies the event for deduplication by the delivery header, or the key filled with the
+/// extracted contents, or the whole body otherwise.
+fn event_key(
+    req: &HttpRequest,
+    body: &str,
+    dedup_cfg: &DedupConfig,
+    notify_cfg: &NotifyConfig,
+    runtime_cfg: &RuntimeConfig,
+) -> String {
+    if let Some(header) = &dedup_cfg.header {
+        if let Some(value) = req.headers().get(header).and_then(|v| v.to_str().ok()) {
+            return value.to_string();
+        }
+    }
+
+    if let Some(template) = &dedup_cfg.key {
+        match serde_json::from_str::<Value>(body) {
+            Ok(val) => {
+                let contents = extract_contents(&val, &extractors_of(notify_cfg, runtime_cfg));
+                match strfmt(template, &contents) {
+                    Ok(key) => return key,
+                    Err(err) => warn!(format!("Failed to format dedup key: {}", err)),
+                }
+            }
+            Err(err) => warn!(format!("Failed to parse body as json: {}", err)),
+        }
+    }
+
+    hex::encode(Sha256::digest(body))
+}
+
+/// Extractors of the notification, including the ones of its preset.
+fn extractors_of(
+    notify_cfg: &NotifyConfig,
+    runtime_cfg: &RuntimeConfig,
+) -> Vec<ContentExtractConfig> {
+    match notify_cfg
+        .preset
+        .as_ref()
+        .and_then(|name| runtime_cfg.presets.get(name))
+    {
+        Some(preset) => preset.merge_extractors(notify_cfg),
+        None => notify_cfg.extractors.clone().unwrap_or_default(),
+    }
+}
+
+/// Sends the messages of the notification rendered from the body, returning the response and
+/// whether the event is delivered, which is false if it's rejected before anything is sent.
+pub async fn handle_notify_request(
     req: &str,
     notify_cfg: &NotifyConfig,
     runtime_cfg: &RuntimeConfig,
     client: &Client,
-) {
-    let mut msg: String = notify_cfg.message.clone();
+    limiter: Data<RateLimiter>,
+    digester: Data<Digester>,
+    client_ip: Option<IpAddr>,
+) -> (HttpResponse, bool) {
+    if runtime_cfg.dry_run(notify_cfg) {
+        let payloads = payloads_of(&render_messages(req, notify_cfg, runtime_cfg));
+        info!(format!(
+            "Dry run. Nothing is sent. Payloads: \n\n{}\n",
+            serde_json::to_string_pretty(&payloads).unwrap_or_default()
+        ));
+        return (
+            HttpResponse::Ok().json(json!({ "dry_run": true, "payloads": payloads })),
+            true,
+        );
+    }
 
-    if notify_cfg.extra.unwrap_or(false) {
-        if notify_cfg.extractors.is_some() && !notify_cfg.extractors.as_ref().unwrap().is_empty() {
-            let mut contents: HashMap<String, String> = HashMap::new();
+    let sender = Sender {
+        id: notify_cfg.id.clone(),
+        limits: [
+            ("server", runtime_cfg.server.rate_limit.as_ref()),
+            (notify_cfg.id.as_str(), notify_cfg.rate_limit.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(scope, limit)| limit.map(|limit| (scope.to_string(), limit.clone())))
+        .collect(),
+        digest: notify_cfg.digest.clone(),
+        limiter: limiter.clone(),
+        digester,
+        client: client.clone(),
+        onebot: runtime_cfg.onebot.clone(),
+    };
 
-            for extract in notify_cfg.extractors.as_ref().unwrap() {
-                let val: Result<Value, serde_json::Error> = serde_json::from_str(req);
-                if let Err(err) = val {
-                    warn!(format!("Failed to parse body as json: {}", err));
-                } else {
-                    let sep = extract.sep.clone().unwrap_or(" ".to_string());
+    let exceeded = limiter.check_event(&sender.limits, client_ip).err();
+    if let Some(exceeded) = &exceeded {
+        match exceeded.action {
+            RateLimitAction::Reject => {
+                warn!("Rate limit exceeded. Rejected.");
+                return (
+                    HttpResponse::TooManyRequests().body("Too Many Requests."),
+                    false,
+                );
+            }
+            RateLimitAction::Drop => {
+                warn!("Rate limit exceeded. Dropped.");
+                return (HttpResponse::Ok().finish(), true);
+            }
+            RateLimitAction::Coalesce => {}
+        }
+    }
 
-                    if let Some(res) =
-                        extract_arg(&val.unwrap(), &extract.path, &sep).or(extract.fallback.clone())
-                    {
-                        contents.insert(extract.name.clone(), res);
-                    }
+    let messages = render_messages(req, notify_cfg, runtime_cfg);
+
+    let messages = match &notify_cfg.quiet {
+        Some(quiet_cfg) => match quiet::quiet_until(quiet_cfg, Utc::now()) {
+            Some(until) => match quiet_cfg.action.unwrap_or(QuietAction::Hold) {
+                QuietAction::Hold => {
+                    info!(format!("Quiet hours until {}. Held back.", until));
+                    quiet::hold(messages, until, sender);
+                    return (HttpResponse::Accepted().finish(), true);
                 }
-            }
+                QuietAction::Drop => {
+                    info!(format!("Quiet hours until {}. Dropped.", until));
+                    return (HttpResponse::Ok().finish(), true);
+                }
+                QuietAction::Redirect => {
+                    info!(format!("Quiet hours until {}. Redirected.", until));
+                    quiet::redirect(quiet_cfg, messages)
+                }
+            },
+            None => messages,
+        },
+        None => messages,
+    };
 
-            if let Ok(res) = strfmt(msg.as_str(), &contents) {
-                msg = res;
-            } else {
-                warn!("Failed to format message with extracted contents.")
+    // Digests are sent once per window anyway, so events exceeding the limits are buffered as well
+    if notify_cfg.digest.is_some() {
+        sender.deliver(messages);
+        return (HttpResponse::Accepted().finish(), true);
+    }
+
+    if let Some(exceeded) = exceeded {
+        warn!(format!(
+            "Rate limit exceeded. Held back for {}s.",
+            exceeded.wait.as_secs()
+        ));
+        RateLimiter::coalesce(limiter, &notify_cfg.id, messages, exceeded.wait, sender);
+        return (HttpResponse::Accepted().finish(), true);
+    }
+
+    let targets: usize = messages.iter().map(|msg| msg.targets().len()).sum();
+    let rejected = sender.send(messages);
+    info!("Notification Sent!");
+
+    // Retrying an event partially sent would send it to the same targets again
+    match rejected {
+        0 => (HttpResponse::Ok().finish(), true),
+        rejected => (
+            HttpResponse::TooManyRequests().body("Too Many Requests."),
+            rejected < targets,
+        ),
+    }
+}
+
+/// Sends the messages of a notification to their targets, within the per target limits.
+#[derive(Clone)]
+pub struct Sender {
+    // ID of the notification, keying the messages held back
+    pub id: String,
+    // Limits of the server and the notification, keyed by their scope
+    pub limits: Vec<(String, RateLimitConfig)>,
+    pub digest: Option<DigestConfig>,
+    pub limiter: Data<RateLimiter>,
+    pub digester: Data<Digester>,
+    pub client: Client,
+    pub onebot: OneBotConfig,
+}
+
+impl Sender {
+    /// Buffers the messages for the digest if the notification has one, or sends them otherwise.
+    pub fn deliver(&self, messages: Vec<Message>) {
+        match &self.digest {
+            Some(digest_cfg) => {
+                info!(format!(
+                    "Buffered {} message(s) for the digest.",
+                    messages.len()
+                ));
+                Digester::add(self.digester.clone(), digest_cfg, messages, self.clone());
+            }
+            None => {
+                self.send(messages);
             }
-        } else {
-            warn!("EXTRA is set to true, but no extractors are defined.")
         }
     }
-    info!(format!("Sending Message: \n\n{}\n", msg));
-
-    if let Some(user) = &notify_cfg.users {
-        let send_private_url = format!("{}/send_private_msg", runtime_cfg.onebot.url);
-        for person in user {
-            tokio::spawn(
-                client
-                    .post(&send_private_url)
-                    .body(
-                        json!({
-                            "user_id": person,
-                            "message": [
-                                {
-                                    "type": "text",
-                                    "data": {
-                                        "text": msg
-                                    }
-                                }
-                            ]
-                        })
-                        .to_string(),
-                    )
-                    .send(),
-            );
+
+    /// Sends the messages, returning the number of targets rejected by the limits.
+    pub fn send(&self, messages: Vec<Message>) -> usize {
+        let mut rejected = 0;
+        for msg in messages {
+            info!(format!("Sending Message: \n\n{}\n", msg.text));
+
+            for target in msg.targets() {
+                let Err(exceeded) = self.limiter.check_target(&self.limits, &target) else {
+                    onebot::send(&self.client, &self.onebot, &msg.to(&target));
+                    continue;
+                };
+                match exceeded.action {
+                    RateLimitAction::Reject => {
+                        warn!(format!("Rate limit exceeded for {}. Rejected.", target));
+                        rejected += 1;
+                    }
+                    RateLimitAction::Drop => {
+                        warn!(format!("Rate limit exceeded for {}. Dropped.", target));
+                    }
+                    RateLimitAction::Coalesce => {
+                        warn!(format!(
+                            "Rate limit exceeded for {}. Held back for {}s.",
+                            target,
+                            exceeded.wait.as_secs()
+                        ));
+                        RateLimiter::coalesce(
+                            self.limiter.clone(),
+                            &self.id,
+                            vec![msg.to(&target)],
+                            exceeded.wait,
+                            self.clone(),
+                        );
+                    }
+                }
+            }
         }
+        rejected
     }
+}
 
-    if let Some(groups) = &notify_cfg.groups {
-        let send_group_url = format!("{}/send_group_msg", runtime_cfg.onebot.url);
+/// The OneBot actions and payloads the messages would be sent with.
+pub fn payloads_of(messages: &[Message]) -> Vec<Value> {
+    messages
+        .iter()
+        .flat_map(|msg| msg.payloads())
+        .map(|(action, payload)| json!({ "action": action, "payload": payload }))
+        .collect()
+}
 
-        if let Some(mentions) = &notify_cfg.mentions {
-            msg = format!("{}\n", msg);
-            for mention in mentions {
-                msg = format!("{} [CQ:at,qq={}]", msg, mention);
-            }
+pub fn render_messages(
+    req: &str,
+    notify_cfg: &NotifyConfig,
+    runtime_cfg: &RuntimeConfig,
+) -> Vec<Message> {
+    if let Some(name) = &notify_cfg.preset {
+        if let Some(preset) = runtime_cfg.presets.get(name) {
+            return preset.render(req, notify_cfg);
         }
+        warn!(format!("Unknown preset [{}]. Nothing will be sent.", name));
+        return vec![];
+    }
+
+    let mut msg = notify_cfg.message.clone().unwrap_or_default();
 
-        for group in groups {
-            tokio::spawn(
-                client
-                    .post(&send_group_url)
-                    .body(
-                        json!({
-                            "group_id": group,
-                            "message": [
-                                {
-                                    "type": "text",
-                                    "data": {
-                                        "text": msg
-                                    }
-                                }
-                            ]
-                        })
-                        .to_string(),
-                    )
-                    .send(),
-            );
+    if notify_cfg.extra.unwrap_or(false) {
+        match &notify_cfg.extractors {
+            Some(extractors) if !extractors.is_empty() => {
+                let val: Result<Value, serde_json::Error> = serde_json::from_str(req);
+                match val {
+                    Ok(val) => msg = format_message(&msg, &extract_contents(&val, extractors)),
+                    Err(err) => warn!(format!("Failed to parse body as json: {}", err)),
+                }
+            }
+            _ => warn!("EXTRA is set to true, but no extractors are defined."),
         }
     }
 
-    info!("Notification Sent!");
+    vec![Message::new(msg, notify_cfg)]
 }
 
-fn extract_arg(val: &Value, path: &str, sep: &str) -> Option<String> {
-    extract_arg_impl(val.clone(), &path.split('.').collect(), sep, 0)
+/// Runs every extractor over the body, skipping the ones that yield nothing.
+pub fn extract_contents(
+    val: &Value,
+    extractors: &[ContentExtractConfig],
+) -> HashMap<String, String> {
+    let (contents, warnings) = extract_with_warnings(val, extractors);
+    for w in warnings {
+        warn!(w);
+    }
+    contents
 }
 
-fn extract_arg_impl(val: Value, paths: &Vec<&str>, sep: &str, idx: usize) -> Option<String> {
-    if idx >= paths.len() {
-        return val.as_str().map(|s| s.to_string());
+/// Runs every extractor over the body, returning the problems found instead of logging them.
+pub fn extract_with_warnings(
+    val: &Value,
+    extractors: &[ContentExtractConfig],
+) -> (HashMap<String, String>, Vec<String>) {
+    let mut contents: HashMap<String, String> = HashMap::new();
+    let mut warnings = vec![];
+
+    for extract in extractors {
+        let sep = extract.sep.clone().unwrap_or(" ".to_string());
+
+        let mut skipped = vec![];
+        let res = extract_arg(val, &extract.path, &sep, extract.limit, &mut skipped);
+        warnings.extend(
+            skipped
+                .iter()
+                .map(|e| format!("Extractor [{}]: {}. Skipped.", extract.name, e)),
+        );
+        let res = match res {
+            Ok(res) => Some(res),
+            Err(e) => {
+                warnings.push(format!("Extractor [{}]: {}.", extract.name, e));
+                extract.fallback.clone()
+            }
+        };
+        if let Some(res) = res {
+            contents.insert(extract.name.clone(), res);
+        }
     }
 
-    let current_path = paths[idx];
-    let mut val = val;
+    (contents, warnings)
+}
+
+/// Fills the template with the extracted contents, leaving it untouched on failure.
+pub fn format_message(template: &str, contents: &HashMap<String, String>) -> String {
+    if let Ok(res) = strfmt(template, contents) {
+        res
+    } else {
+        warn!("Failed to format message with extracted contents.");
+        template.to_string()
+    }
+}
 
-    if current_path.starts_with('[') && current_path.ends_with(']') {
-        if !val.is_array() {
-            warn!(format!(
-                "The value at path: {} is not an array",
-                paths[..=idx].join(".")
+/// Checks the syntax of an extractor path, such as `commits.[0,-1].message`, `commits.[0:3]` or
+/// `payload.$.id`.
+pub fn validate_path(path: &str) -> Result<(), String> {
+    for segment in path.split('.') {
+        if segment.is_empty() {
+            return Err(format!("empty segment in path {}", path));
+        }
+        if let Some(idxs) = segment.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            if let Some(i) = idxs
+                .split(',')
+                .find(|i| !idxs.is_empty() && Selector::parse(i).is_none())
+            {
+                return Err(format!("unrecognized index {} in path {}", i, path));
+            }
+        } else if segment.contains(['[', ']']) {
+            return Err(format!(
+                "indices must be a segment of their own, e.g. items.[0], in path {}",
+                path
             ));
-            return None;
         }
-        let arr = val.as_array().unwrap();
+    }
+    Ok(())
+}
 
-        let idxs = current_path[1..current_path.len() - 1].to_string();
-        let mut range = vec![];
+/// Why an extractor path yields nothing, along with the path up to the failing segment.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ExtractError {
+    MissingKey {
+        path: String,
+    },
+    NotAnArray {
+        path: String,
+    },
+    BadIndex {
+        path: String,
+        index: String,
+    },
+    OutOfBounds {
+        path: String,
+        index: i64,
+        len: usize,
+    },
+    /// The string at `$` isn't valid JSON.
+    Json {
+        path: String,
+        error: String,
+    },
+    /// The path ends at a value which isn't a string.
+    NotAString {
+        path: String,
+    },
+}
 
-        if idxs.is_empty() {
-            for i in 0..arr.len() {
-                range.push(i);
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::MissingKey { path } => write!(f, "cannot find the key at {}", path),
+            ExtractError::NotAnArray { path } => write!(f, "the value at {} is not an array", path),
+            ExtractError::BadIndex { path, index } => {
+                write!(f, "unrecognized index {} at {}", index, path)
             }
-        } else {
-            let idxs: Vec<&str> = idxs.split(',').collect();
-            for i in idxs {
-                if let Ok(i) = i.parse() {
-                    range.push(i);
-                } else {
-                    warn!(format!(
-                        "Unrecognized Index: {} at {}",
-                        i,
-                        paths[..=idx].join(".")
-                    ));
-                    return None;
-                }
+            ExtractError::OutOfBounds { path, index, len } => write!(
+                f,
+                "index {} is out of bounds at {}, which has {} element(s)",
+                index, path, len
+            ),
+            ExtractError::Json { path, error } => {
+                write!(
+                    f,
+                    "failed to parse the value at {} as json: {}",
+                    path, error
+                )
             }
+            ExtractError::NotAString { path } => write!(f, "the value at {} is not a string", path),
         }
+    }
+}
 
-        let mut results = vec![];
-        for i in range {
-            let res = extract_arg_impl(arr[i].clone(), paths, sep, idx + 1);
-            if let Some(res) = res {
-                results.push(res);
+/// An element of an index segment, such as `1`, `-1`, `last` or `0:3`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Selector {
+    /// Counts from the end if negative.
+    Index(i64),
+    /// The elements from the start to the end exclusively, clamped to the array.
+    Range(Option<i64>, Option<i64>),
+}
+
+impl Selector {
+    fn parse(s: &str) -> Option<Selector> {
+        let bound = |b: &str| -> Option<Option<i64>> {
+            if b.is_empty() {
+                Some(None)
             } else {
-                warn!(format!(
-                    "The error occurred at index \x1b[1m{}\x1b[0m of the array",
-                    i
-                ));
+                b.parse().ok().map(Some)
             }
+        };
+        match s {
+            "first" => Some(Selector::Index(0)),
+            "last" => Some(Selector::Index(-1)),
+            _ => match s.split_once(':') {
+                Some((start, end)) => Some(Selector::Range(bound(start)?, bound(end)?)),
+                None => s.parse().ok().map(Selector::Index),
+            },
         }
+    }
 
-        return Some(results.join(sep));
+    /// Indices of the array selected, or the index given if it's out of bounds.
+    fn indices(self, len: usize) -> Result<Vec<usize>, i64> {
+        let len = len as i64;
+        let from_end = |i: i64| if i < 0 { len + i } else { i };
+        match self {
+            Selector::Index(i) => match from_end(i) {
+                idx if (0..len).contains(&idx) => Ok(vec![idx as usize]),
+                _ => Err(i),
+            },
+            Selector::Range(start, end) => {
+                let start = start.map_or(0, from_end).clamp(0, len);
+                let end = end.map_or(len, from_end).clamp(0, len);
+                Ok((start..end).map(|i| i as usize).collect())
+            }
+        }
     }
+}
 
-    match current_path {
-        "$" => {
-            let res: Result<Value, serde_json::Error> =
-                serde_json::from_str(val.as_str().unwrap_or_default());
-            if let Err(err) = res {
-                warn!(format!("Failed to parse body as json: {}", err));
-                return None;
-            } else {
-                val = res.unwrap();
+/// Extracts the string at the path, joining the ones of the array elements selected with `sep`.
+/// Only the first `limit` of them are kept if specified, followed by `and N more`.
+///
+/// Elements failing are skipped, with their errors collected into `skipped`. It only fails if all
+/// the elements selected do.
+fn extract_arg(
+    val: &Value,
+    path: &str,
+    sep: &str,
+    limit: Option<usize>,
+    skipped: &mut Vec<ExtractError>,
+) -> Result<String, ExtractError> {
+    let segments: Vec<&str> = path.split('.').collect();
+    let mut results = extract_arg_impl(val, &segments, "", skipped)?;
+
+    if let Some(limit) = limit.filter(|limit| results.len() > *limit) {
+        let more = results.len() - limit;
+        results.truncate(limit);
+        results.push(format!("and {} more", more));
+    }
+    Ok(results.join(sep))
+}
+
+fn extract_arg_impl(
+    val: &Value,
+    segments: &[&str],
+    at: &str,
+    skipped: &mut Vec<ExtractError>,
+) -> Result<Vec<String>, ExtractError> {
+    let join = |segment: &str| {
+        if at.is_empty() {
+            segment.to_string()
+        } else {
+            format!("{}.{}", at, segment)
+        }
+    };
+    let Some((segment, rest)) = segments.split_first() else {
+        return val
+            .as_str()
+            .map(|s| vec![s.to_string()])
+            .ok_or(ExtractError::NotAString {
+                path: at.to_string(),
+            });
+    };
+
+    if let Some(idxs) = segment.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        let Some(arr) = val.as_array() else {
+            return Err(ExtractError::NotAnArray {
+                path: join(segment),
+            });
+        };
+
+        let selectors: Vec<Selector> = if idxs.is_empty() {
+            vec![Selector::Range(None, None)]
+        } else {
+            idxs.split(',')
+                .map(|i| {
+                    Selector::parse(i).ok_or(ExtractError::BadIndex {
+                        path: join(segment),
+                        index: i.to_string(),
+                    })
+                })
+                .collect::<Result<_, _>>()?
+        };
+
+        let mut results = vec![];
+        let mut failed = vec![];
+        for selector in selectors {
+            let indices = match selector.indices(arr.len()) {
+                Ok(indices) => indices,
+                Err(i) => {
+                    failed.push(ExtractError::OutOfBounds {
+                        path: join(&format!("[{}]", i)),
+                        index: i,
+                        len: arr.len(),
+                    });
+                    continue;
+                }
+            };
+            for i in indices {
+                match extract_arg_impl(&arr[i], rest, &join(&format!("[{}]", i)), skipped) {
+                    Ok(res) => results.extend(res),
+                    Err(e) => failed.push(e),
+                }
             }
         }
-        _ => {
-            if let Some(field) = val.get(current_path) {
-                val = field.clone();
-            } else {
-                warn!(format!(
-                    "Cannot find the specified extract path: {}",
-                    paths[..=idx].join(".")
-                ));
-                return None;
+
+        if results.is_empty() && !failed.is_empty() {
+            return Err(failed.remove(0));
+        }
+        skipped.extend(failed);
+        return Ok(results);
+    }
+
+    let at = join(segment);
+    match *segment {
+        "$" => {
+            let Some(embedded) = val.as_str() else {
+                return Err(ExtractError::NotAString { path: at });
+            };
+            match serde_json::from_str::<Value>(embedded) {
+                Ok(val) => extract_arg_impl(&val, rest, &at, skipped),
+                Err(err) => Err(ExtractError::Json {
+                    path: at,
+                    error: err.to_string(),
+                }),
             }
         }
+        key => match val.get(key) {
+            Some(field) => extract_arg_impl(field, rest, &at, skipped),
+            None => Err(ExtractError::MissingKey { path: at }),
+        },
     }
-    extract_arg_impl(val, paths, sep, idx + 1)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::extract_arg;
+    use super::{extract_arg, extract_with_warnings, payloads_of, validate_path, ExtractError};
+    use crate::{config::ContentExtractConfig, onebot::Message};
     use serde_json::json;
 
     #[test]
+    fn test_extract_with_warnings() {
+        let extractors: Vec<ContentExtractConfig> = serde_json::from_value(json!([
+            { "name": "user", "path": "sender.name" },
+            { "name": "repo", "path": "repository.name", "fallback": "unknown" },
+            { "name": "ids", "path": "commits.[].id", "sep": "," }
+        ]))
+        .unwrap();
+        let val = json!({
+            "sender": { "name": "bob" },
+            "commits": [{ "id": "a" }, {}, { "id": "c" }]
+        });
+
+        let (contents, warnings) = extract_with_warnings(&val, &extractors);
+        assert_eq!(contents["user"], "bob");
+        assert_eq!(contents["repo"], "unknown");
+        assert_eq!(contents["ids"], "a,c");
+        assert_eq!(
+            warnings,
+            vec![
+                "Extractor [repo]: cannot find the key at repository.",
+                "Extractor [ids]: cannot find the key at commits.[1].id. Skipped.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_payloads_of() {
+        let msg = Message {
+            text: "hi".to_string(),
+            groups: vec!["1".to_string()],
+            users: vec!["2".to_string()],
+            mentions: vec![],
+        };
+        assert_eq!(
+            payloads_of(&[msg]),
+            vec![
+                json!({
+                    "action": "send_private_msg",
+                    "payload": { "user_id": "2", "message": [{ "type": "text", "data": { "text": "hi" } }] }
+                }),
+                json!({
+                    "action": "send_group_msg",
+                    "payload": { "group_id": "1", "message": [{ "type": "text", "data": { "text": "hi" } }] }
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_to_owned)]
     fn test_extract_arg() {
         let val = json!(
             {
@@ -271,26 +834,150 @@ mod tests {
         );
 
         let sep = ",";
-        assert_eq!(extract_arg(&val, &"simple".to_string(), sep).unwrap(), "1");
         assert_eq!(
-            extract_arg(&val, &"nesting.so.deep".to_string(), sep).unwrap(),
+            extract_arg(&val, &"simple".to_string(), sep, None, &mut vec![]).unwrap(),
+            "1"
+        );
+        assert_eq!(
+            extract_arg(&val, &"nesting.so.deep".to_string(), sep, None, &mut vec![]).unwrap(),
             "2"
         );
         assert_eq!(
-            extract_arg(&val, &"list.[]".to_string(), sep).unwrap(),
+            extract_arg(&val, &"list.[]".to_string(), sep, None, &mut vec![]).unwrap(),
             "3,4,5"
         );
         assert_eq!(
-            extract_arg(&val, &"list.[1,2]".to_string(), sep).unwrap(),
+            extract_arg(&val, &"list.[1,2]".to_string(), sep, None, &mut vec![]).unwrap(),
             "4,5"
         );
         assert_eq!(
-            extract_arg(&val, &"nesting-list.[].nested".to_string(), sep).unwrap(),
+            extract_arg(
+                &val,
+                &"nesting-list.[].nested".to_string(),
+                sep,
+                None,
+                &mut vec![]
+            )
+            .unwrap(),
             "6,7"
         );
         assert_eq!(
-            extract_arg(&val, &"nesting-list.[].special".to_string(), sep).unwrap(),
+            extract_arg(
+                &val,
+                &"nesting-list.[].special".to_string(),
+                sep,
+                None,
+                &mut vec![]
+            )
+            .unwrap(),
             "8"
         );
     }
+
+    #[test]
+    fn test_extract_errors() {
+        let val = json!({
+            "count": 1,
+            "list": ["a", "b"],
+            "items": [{ "id": "x" }, { "name": "y" }],
+            "raw": "{\"id\": \"z\"",
+        });
+        let extract = |path: &str| {
+            let mut skipped = vec![];
+            extract_arg(&val, path, ",", None, &mut skipped).map(|res| (res, skipped))
+        };
+        let path = |path: &str| path.to_string();
+
+        assert_eq!(
+            extract("missing.id"),
+            Err(ExtractError::MissingKey {
+                path: path("missing")
+            })
+        );
+        assert_eq!(
+            extract("count.[0]"),
+            Err(ExtractError::NotAnArray {
+                path: path("count.[0]")
+            })
+        );
+        assert_eq!(
+            extract("list.[x]"),
+            Err(ExtractError::BadIndex {
+                path: path("list.[x]"),
+                index: path("x")
+            })
+        );
+        assert_eq!(
+            extract("list.[5]"),
+            Err(ExtractError::OutOfBounds {
+                path: path("list.[5]"),
+                index: 5,
+                len: 2
+            })
+        );
+        assert!(matches!(
+            extract("raw.$.id"),
+            Err(ExtractError::Json { path, .. }) if path == "raw.$"
+        ));
+        assert_eq!(
+            extract("count"),
+            Err(ExtractError::NotAString {
+                path: path("count")
+            })
+        );
+        assert_eq!(
+            extract("count.$.id"),
+            Err(ExtractError::NotAString {
+                path: path("count.$")
+            })
+        );
+        assert_eq!(
+            extract("items.[].id"),
+            Ok((
+                path("x"),
+                vec![ExtractError::MissingKey {
+                    path: path("items.[1].id")
+                }]
+            ))
+        );
+        assert_eq!(
+            extract("list.[1,3]"),
+            Ok((
+                path("b"),
+                vec![ExtractError::OutOfBounds {
+                    path: path("list.[3]"),
+                    index: 3,
+                    len: 2
+                }]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let val = json!({ "list": ["a", "b", "c", "d", "e"], "empty": [] });
+        let extract =
+            |path: &str, limit: Option<usize>| extract_arg(&val, path, ",", limit, &mut vec![]);
+
+        assert_eq!(extract("list.[-1]", None).unwrap(), "e");
+        assert_eq!(extract("list.[0,-2]", None).unwrap(), "a,d");
+        assert_eq!(extract("list.[first,last]", None).unwrap(), "a,e");
+        assert_eq!(extract("list.[0:3]", None).unwrap(), "a,b,c");
+        assert_eq!(extract("list.[3:]", None).unwrap(), "d,e");
+        assert_eq!(extract("list.[:-3]", None).unwrap(), "a,b");
+        assert_eq!(extract("list.[-2:10]", None).unwrap(), "d,e");
+        assert_eq!(extract("list.[4:2]", None).unwrap(), "");
+        assert_eq!(extract("list.[]", Some(2)).unwrap(), "a,b,and 3 more");
+        assert_eq!(extract("list.[0:2]", Some(2)).unwrap(), "a,b");
+        assert_eq!(extract("empty.[]", None).unwrap(), "");
+
+        // Indices past the end used to panic the worker
+        assert!(extract("list.[5]", None).is_err());
+        assert!(extract("list.[-6]", None).is_err());
+        assert!(extract("empty.[first]", None).is_err());
+
+        assert!(validate_path("list.[0,-1,first,last,1:3,:2,-2:]").is_ok());
+        assert!(validate_path("list.[1:x]").is_err());
+        assert!(validate_path("list.[middle]").is_err());
+    }
 }
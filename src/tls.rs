@@ -0,0 +1,94 @@
+use std::{any::Any, sync::Arc};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
+
+use crate::config::TlsConfig;
+
+/// Identities of the verified client certificate of a connection, namely its subject common
+/// names and subject alternative names.
+#[derive(Clone)]
+pub struct ClientIdentity(pub Vec<String>);
+
+pub fn server_config(cfg: &TlsConfig) -> Result<ServerConfig, String> {
+    let certs = CertificateDer::pem_file_iter(&cfg.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to load certificate {}: {}", cfg.cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(&cfg.key)
+        .map_err(|e| format!("Failed to load private key {}: {}", cfg.key, e))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let builder = if let Some(client_ca) = &cfg.client_ca {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(client_ca)
+            .map_err(|e| format!("Failed to load client CA {}: {}", client_ca, e))?
+        {
+            let cert =
+                cert.map_err(|e| format!("Failed to load client CA {}: {}", client_ca, e))?;
+            roots.add(cert).map_err(|e| e.to_string())?;
+        }
+
+        // Client certificates are only required by the notifications authenticating with mTLS.
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .allow_unauthenticated()
+            .build()
+            .map_err(|e| e.to_string())?;
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    builder
+        .with_single_cert(certs, key)
+        .map_err(|e| e.to_string())
+}
+
+/// Stores the [`ClientIdentity`] of TLS connections with a verified client certificate.
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+
+    let (_, session) = tls.get_ref();
+    if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+        ext.insert(ClientIdentity(identities(cert)));
+    }
+}
+
+fn identities(cert: &CertificateDer) -> Vec<String> {
+    let mut identities = vec![];
+
+    let Ok((_, cert)) = x509_parser::certificate::X509Certificate::from_der(cert) else {
+        return identities;
+    };
+
+    for cn in cert.subject().iter_common_name() {
+        if let Ok(cn) = cn.as_str() {
+            identities.push(cn.to_string());
+        }
+    }
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => identities.push(name.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    identities
+}